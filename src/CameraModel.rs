@@ -1,6 +1,539 @@
-use ndarray::Array1;
+use std::f64::consts::PI;
+use std::path::Path;
+use nalgebra::{Isometry3, Matrix2, Point3, Vector2, Vector3};
+use ndarray::{array, Array1, Array2};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::error::DetectorError;
+use crate::primitive::Ellipse;
+use crate::projections::project_point_into_image_plane;
 
+// Pinhole intrinsics in pixels. The detector works in an internal image frame centered on the
+// principal point with square pixels of focal length fx, so y is rescaled by fx / fy.
 pub struct CameraModel {
-    pub focal_length: f64,
-    pub resolution: Array1<f64>
-}
\ No newline at end of file
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub resolution: Array1<f64>,
+    pub lens: LensModel,
+    // Rigid transform from the camera frame into a head or device frame, in mm
+    pub extrinsics: Option<Isometry3<f64>>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LensModel {
+    Pinhole,
+    RadialTangential(RadialTangentialDistortion),
+    KannalaBrandt(KannalaBrandtDistortion)
+}
+
+// OpenCV's rational radial-tangential model, coefficients in the order k1, k2, p1, p2, k3, k4, k5, k6
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RadialTangentialDistortion {
+    pub k1: f64,
+    pub k2: f64,
+    pub p1: f64,
+    pub p2: f64,
+    pub k3: f64,
+    pub k4: f64,
+    pub k5: f64,
+    pub k6: f64
+}
+
+// OpenCV's fisheye model, the distorted radius is theta (1 + k1 theta^2 + k2 theta^4 + k3 theta^6 + k4 theta^8)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KannalaBrandtDistortion {
+    pub k1: f64,
+    pub k2: f64,
+    pub k3: f64,
+    pub k4: f64
+}
+
+// One entry of a Pupil Capture intrinsics file, keyed by "(width, height)"
+#[derive(Debug, Deserialize, Serialize)]
+struct IntrinsicsEntry {
+    camera_matrix: Vec<Vec<f64>>,
+    dist_coefs: Value,
+    resolution: Vec<f64>,
+    cam_type: String
+}
+
+// Contour samples used to carry an ellipse through the distortion
+const ELLIPSE_CONTOUR_SAMPLES: usize = 32;
+
+impl CameraModel {
+    // Square pixels and the principal point in the image center
+    pub fn new(focal_length: f64, resolution: Array1<f64>) -> CameraModel {
+        Self::from_intrinsics(focal_length, focal_length, resolution[0] / 2.0, resolution[1] / 2.0, resolution)
+    }
+
+    pub fn from_intrinsics(fx: f64, fy: f64, cx: f64, cy: f64, resolution: Array1<f64>) -> CameraModel {
+        CameraModel {
+            fx,
+            fy,
+            cx,
+            cy,
+            resolution,
+            lens: LensModel::Pinhole,
+            extrinsics: None
+        }
+    }
+
+    pub fn with_lens(mut self, lens: LensModel) -> CameraModel {
+        self.lens = lens;
+        self
+    }
+
+    pub fn with_extrinsics(mut self, extrinsics: Isometry3<f64>) -> CameraModel {
+        self.extrinsics = Some(extrinsics);
+        self
+    }
+
+    pub fn with_distortion(self, dist_coefs: &[f64]) -> CameraModel {
+        self.with_lens(LensModel::RadialTangential(RadialTangentialDistortion::from_coefficients(dist_coefs)))
+    }
+
+    pub fn with_fisheye_distortion(self, dist_coefs: &[f64]) -> CameraModel {
+        self.with_lens(LensModel::KannalaBrandt(KannalaBrandtDistortion::from_coefficients(dist_coefs)))
+    }
+
+    // The same camera in a capture mode whose pixels are scale * pixel + offset, like binning or a ROI crop.
+    // Distortion acts on normalized coordinates and carries over unchanged.
+    pub fn transformed(&self, scale: &Array1<f64>, offset: &Array1<f64>, resolution: Array1<f64>) -> CameraModel {
+        CameraModel {
+            fx: scale[0] * self.fx,
+            fy: scale[1] * self.fy,
+            cx: scale[0] * self.cx + offset[0],
+            cy: scale[1] * self.cy + offset[1],
+            resolution,
+            lens: self.lens.clone(),
+            extrinsics: self.extrinsics
+        }
+    }
+
+    // Full sensor at another resolution, e.g. 400x400 binned down to 192x192
+    pub fn resized(&self, resolution: Array1<f64>) -> CameraModel {
+        let scale = &resolution / &self.resolution;
+        self.transformed(&scale, &array![0.0, 0.0], resolution)
+    }
+
+    // Region of interest with its top left corner at (x, y)
+    pub fn cropped(&self, x: f64, y: f64, resolution: Array1<f64>) -> CameraModel {
+        self.transformed(&array![1.0, 1.0], &array![-x, -y], resolution)
+    }
+
+    // Pupil Capture's *.intrinsics files are msgpack, anything ending in .json is read as JSON
+    pub fn load_intrinsics(path: &Path, resolution: Array1<f64>) -> Result<CameraModel, DetectorError> {
+        let intrinsics = Self::read_intrinsics_file(path)?;
+        let key = Self::intrinsics_key(&resolution);
+        let entry = intrinsics.get(&key)
+            .ok_or_else(|| DetectorError::InvalidCamera(format!("no intrinsics for resolution {} in {}", key, path.display())))?;
+        let entry: IntrinsicsEntry = serde_json::from_value(entry.clone())?;
+
+        let camera_matrix = &entry.camera_matrix;
+        if camera_matrix.len() != 3 || camera_matrix.iter().any(|row| row.len() != 3) {
+            return Err(DetectorError::InvalidCamera(format!("camera matrix for {} is not 3x3", key)));
+        }
+        let camera = Self::from_intrinsics(camera_matrix[0][0], camera_matrix[1][1], camera_matrix[0][2], camera_matrix[1][2], resolution);
+
+        // Coefficients are stored as [[k1, k2, ...]], flatten whatever nesting is there
+        let mut dist_coefs = Vec::new();
+        Self::flatten_coefficients(&entry.dist_coefs, &mut dist_coefs);
+        match entry.cam_type.as_str() {
+            "dummy" => Ok(camera),
+            "radial" => Ok(camera.with_distortion(&dist_coefs)),
+            "fisheye" => Ok(camera.with_fisheye_distortion(&dist_coefs)),
+            cam_type => Err(DetectorError::InvalidCamera(format!("unknown camera type {}", cam_type)))
+        }
+    }
+
+    // Adds or replaces the entry for this resolution and keeps the other resolutions in the file
+    pub fn save_intrinsics(&self, path: &Path) -> Result<(), DetectorError> {
+        let mut intrinsics = if path.exists() {
+            Self::read_intrinsics_file(path)?
+        } else {
+            let mut intrinsics = Map::new();
+            intrinsics.insert("version".to_owned(), Value::from(1));
+            intrinsics
+        };
+
+        let (cam_type, dist_coefs) = match &self.lens {
+            LensModel::Pinhole => ("dummy", vec![0.0; 5]),
+            // Pupil Capture writes five coefficients unless the rational model is in use
+            LensModel::RadialTangential(d) if d.k4 == 0.0 && d.k5 == 0.0 && d.k6 == 0.0 => ("radial", vec![d.k1, d.k2, d.p1, d.p2, d.k3]),
+            LensModel::RadialTangential(d) => ("radial", vec![d.k1, d.k2, d.p1, d.p2, d.k3, d.k4, d.k5, d.k6]),
+            LensModel::KannalaBrandt(d) => ("fisheye", vec![d.k1, d.k2, d.k3, d.k4])
+        };
+        let entry = IntrinsicsEntry {
+            camera_matrix: vec![
+                vec![self.fx, 0.0, self.cx],
+                vec![0.0, self.fy, self.cy],
+                vec![0.0, 0.0, 1.0]
+            ],
+            dist_coefs: Value::from(vec![dist_coefs]),
+            resolution: self.resolution.to_vec(),
+            cam_type: cam_type.to_owned()
+        };
+        intrinsics.insert(Self::intrinsics_key(&self.resolution), serde_json::to_value(entry)?);
+
+        let buf = if Self::is_json(path) {
+            serde_json::to_vec_pretty(&intrinsics)?
+        } else {
+            rmp_serde::encode::to_vec_named(&intrinsics)?
+        };
+        std::fs::write(path, buf)?;
+        Ok(())
+    }
+
+    fn read_intrinsics_file(path: &Path) -> Result<Map<String, Value>, DetectorError> {
+        let buf = std::fs::read(path)?;
+        if Self::is_json(path) {
+            Ok(serde_json::from_slice(&buf)?)
+        } else {
+            Ok(rmp_serde::decode::from_slice(&buf)?)
+        }
+    }
+
+    fn is_json(path: &Path) -> bool {
+        path.extension().is_some_and(|extension| extension == "json")
+    }
+
+    fn intrinsics_key(resolution: &Array1<f64>) -> String {
+        format!("({}, {})", resolution[0].round() as i64, resolution[1].round() as i64)
+    }
+
+    fn flatten_coefficients(value: &Value, coefficients: &mut Vec<f64>) {
+        match value {
+            Value::Array(values) => values.iter().for_each(|value| Self::flatten_coefficients(value, coefficients)),
+            Value::Number(number) => coefficients.extend(number.as_f64()),
+            _ => {}
+        }
+    }
+
+    pub fn intrinsic_matrix(&self) -> Array2<f64> {
+        array![
+            [self.fx, 0.0, self.cx],
+            [0.0, self.fy, self.cy],
+            [0.0, 0.0, 1.0]
+        ]
+    }
+
+    // Focal length of the internal image frame
+    pub fn focal_length(&self) -> f64 {
+        self.fx
+    }
+
+    // Distorted pixel coordinates to the undistorted internal frame
+    pub fn image_to_internal(&self, point: &Array1<f64>) -> Array1<f64> {
+        let point = self.undistort_point(point);
+        array!(point[0] - self.cx, (point[1] - self.cy) * self.fx / self.fy)
+    }
+
+    pub fn internal_to_image(&self, point: &Array1<f64>) -> Array1<f64> {
+        self.distort_point(&array!(point[0] + self.cx, point[1] * self.fy / self.fx + self.cy))
+    }
+
+    pub fn image_ellipse_to_internal(&self, ellipse: &Ellipse) -> Ellipse {
+        let ellipse = self.undistort_ellipse(ellipse);
+        ellipse.scale_and_translate(1.0, self.fx / self.fy, -self.cx, -self.cy * self.fx / self.fy)
+    }
+
+    pub fn internal_ellipse_to_image(&self, ellipse: &Ellipse) -> Ellipse {
+        let ellipse = ellipse.scale_and_translate(1.0, self.fy / self.fx, self.cx, self.cy);
+        self.distort_ellipse(&ellipse)
+    }
+
+    pub fn undistort_point(&self, point: &Array1<f64>) -> Array1<f64> {
+        let normalized = self.lens.undistort(Vector2::new((point[0] - self.cx) / self.fx, (point[1] - self.cy) / self.fy));
+        array!(normalized.x * self.fx + self.cx, normalized.y * self.fy + self.cy)
+    }
+
+    pub fn distort_point(&self, point: &Array1<f64>) -> Array1<f64> {
+        let normalized = self.lens.distort(Vector2::new((point[0] - self.cx) / self.fx, (point[1] - self.cy) / self.fy));
+        array!(normalized.x * self.fx + self.cx, normalized.y * self.fy + self.cy)
+    }
+
+    // Distortion bends ellipses, so map sampled contour points and refit. Degenerate ellipses pass through unchanged.
+    pub fn undistort_ellipse(&self, ellipse: &Ellipse) -> Ellipse {
+        if self.lens == LensModel::Pinhole {
+            return ellipse.clone()
+        }
+        Self::map_ellipse_contour(ellipse, |point| self.undistort_point(point))
+    }
+
+    pub fn distort_ellipse(&self, ellipse: &Ellipse) -> Ellipse {
+        if self.lens == LensModel::Pinhole {
+            return ellipse.clone()
+        }
+        Self::map_ellipse_contour(ellipse, |point| self.distort_point(point))
+    }
+
+    fn map_ellipse_contour(ellipse: &Ellipse, map: impl Fn(&Array1<f64>) -> Array1<f64>) -> Ellipse {
+        let (cos, sin) = (ellipse.angle.cos(), ellipse.angle.sin());
+        let mut contour = Array2::zeros((ELLIPSE_CONTOUR_SAMPLES, 2));
+        for i in 0..ELLIPSE_CONTOUR_SAMPLES {
+            let t = 2.0 * PI * i as f64 / ELLIPSE_CONTOUR_SAMPLES as f64;
+            let (x, y) = (ellipse.major_radius * t.cos(), ellipse.minor_radius * t.sin());
+            let point = map(&array!(
+                ellipse.center[0] + cos * x - sin * y,
+                ellipse.center[1] + sin * x + cos * y
+            ));
+            contour[[i, 0]] = point[0];
+            contour[[i, 1]] = point[1];
+        }
+
+        Ellipse::fit(contour.view()).unwrap_or_else(|| ellipse.clone())
+    }
+
+    // Camera coordinates into the head frame, None without extrinsics
+    pub fn point_to_head(&self, point: &Array1<f64>) -> Option<Array1<f64>> {
+        let point = self.extrinsics? * Point3::new(point[0], point[1], point[2]);
+        Some(array!(point.x, point.y, point.z))
+    }
+
+    pub fn direction_to_head(&self, direction: &Array1<f64>) -> Option<Array1<f64>> {
+        let direction = self.extrinsics? * Vector3::new(direction[0], direction[1], direction[2]);
+        Some(array!(direction.x, direction.y, direction.z))
+    }
+
+    // Pixel coordinates of a point in camera coordinates
+    pub fn project_point(&self, point: &Array1<f64>) -> Array1<f64> {
+        match &self.lens {
+            // Fisheye lenses see past 90 degrees where the pinhole projection breaks down
+            LensModel::KannalaBrandt(distortion) => {
+                let r = point[0].hypot(point[1]);
+                let theta = r.atan2(point[2]);
+                let scale = if r > 0.0 { distortion.distorted_radius(theta) / r } else { 0.0 };
+                array!(self.fx * scale * point[0] + self.cx, self.fy * scale * point[1] + self.cy)
+            }
+            _ => self.internal_to_image(&project_point_into_image_plane(point.clone(), self.focal_length()))
+        }
+    }
+}
+
+impl LensModel {
+    // Both directions work on normalized image coordinates
+    pub fn distort(&self, point: Vector2<f64>) -> Vector2<f64> {
+        match self {
+            LensModel::Pinhole => point,
+            LensModel::RadialTangential(distortion) => distortion.distort(point),
+            LensModel::KannalaBrandt(distortion) => distortion.distort(point)
+        }
+    }
+
+    pub fn undistort(&self, point: Vector2<f64>) -> Vector2<f64> {
+        match self {
+            LensModel::Pinhole => point,
+            LensModel::RadialTangential(distortion) => distortion.undistort(point),
+            LensModel::KannalaBrandt(distortion) => distortion.undistort(point)
+        }
+    }
+}
+
+impl RadialTangentialDistortion {
+    // Missing trailing coefficients are zero, like in OpenCV
+    pub fn from_coefficients(dist_coefs: &[f64]) -> RadialTangentialDistortion {
+        let coefficient = |i: usize| dist_coefs.get(i).copied().unwrap_or(0.0);
+        RadialTangentialDistortion {
+            k1: coefficient(0),
+            k2: coefficient(1),
+            p1: coefficient(2),
+            p2: coefficient(3),
+            k3: coefficient(4),
+            k4: coefficient(5),
+            k5: coefficient(6),
+            k6: coefficient(7)
+        }
+    }
+
+    pub fn distort(&self, point: Vector2<f64>) -> Vector2<f64> {
+        let (x, y) = (point.x, point.y);
+        let r2 = x * x + y * y;
+        let r4 = r2 * r2;
+        let r6 = r4 * r2;
+        let radial = (1.0 + self.k1 * r2 + self.k2 * r4 + self.k3 * r6) / (1.0 + self.k4 * r2 + self.k5 * r4 + self.k6 * r6);
+        Vector2::new(
+            x * radial + 2.0 * self.p1 * x * y + self.p2 * (r2 + 2.0 * x * x),
+            y * radial + self.p1 * (r2 + 2.0 * y * y) + 2.0 * self.p2 * x * y
+        )
+    }
+
+    // Newton iterations, which unlike OpenCV's fixed point iteration also converge for strong wide-angle distortion
+    pub fn undistort(&self, point: Vector2<f64>) -> Vector2<f64> {
+        let mut undistorted = point;
+        let step = 1e-7;
+        for _ in 0..20 {
+            let residual = self.distort(undistorted) - point;
+            let jacobian = Matrix2::from_columns(&[
+                (self.distort(undistorted + Vector2::new(step, 0.0)) - point - residual) / step,
+                (self.distort(undistorted + Vector2::new(0.0, step)) - point - residual) / step
+            ]);
+            let delta = match jacobian.try_inverse() {
+                Some(inverse) => inverse * -residual,
+                None => break
+            };
+            undistorted += delta;
+            if delta.norm() < 1e-12 {
+                break;
+            }
+        }
+
+        undistorted
+    }
+}
+
+impl KannalaBrandtDistortion {
+    pub fn from_coefficients(dist_coefs: &[f64]) -> KannalaBrandtDistortion {
+        let coefficient = |i: usize| dist_coefs.get(i).copied().unwrap_or(0.0);
+        KannalaBrandtDistortion {
+            k1: coefficient(0),
+            k2: coefficient(1),
+            k3: coefficient(2),
+            k4: coefficient(3)
+        }
+    }
+
+    pub fn distorted_radius(&self, theta: f64) -> f64 {
+        let theta2 = theta * theta;
+        theta * (1.0 + theta2 * (self.k1 + theta2 * (self.k2 + theta2 * (self.k3 + theta2 * self.k4))))
+    }
+
+    pub fn distort(&self, point: Vector2<f64>) -> Vector2<f64> {
+        let r = point.norm();
+        if r == 0.0 {
+            return point
+        }
+        point * self.distorted_radius(r.atan()) / r
+    }
+
+    pub fn undistort(&self, point: Vector2<f64>) -> Vector2<f64> {
+        let distorted_radius = point.norm();
+        if distorted_radius == 0.0 {
+            return point
+        }
+
+        // Newton on theta, then back to the pinhole radius
+        let mut theta = distorted_radius.min(PI / 2.0);
+        for _ in 0..20 {
+            let theta2 = theta * theta;
+            let derivative = 1.0 + theta2 * (3.0 * self.k1 + theta2 * (5.0 * self.k2 + theta2 * (7.0 * self.k3 + theta2 * 9.0 * self.k4)));
+            let delta = (self.distorted_radius(theta) - distorted_radius) / derivative;
+            theta -= delta;
+            if delta.abs() < 1e-12 {
+                break;
+            }
+        }
+
+        point * theta.tan() / distorted_radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lens_models() -> Vec<LensModel> {
+        vec![
+            LensModel::Pinhole,
+            LensModel::RadialTangential(RadialTangentialDistortion::from_coefficients(&[-0.4, 0.2, 0.001, -0.002, -0.05])),
+            LensModel::RadialTangential(RadialTangentialDistortion::from_coefficients(&[2.1, 0.8, 0.0005, 0.001, 0.02, 2.4, 1.3, 0.1])),
+            LensModel::KannalaBrandt(KannalaBrandtDistortion::from_coefficients(&[-0.05, 0.01, -0.003, 0.0005]))
+        ]
+    }
+
+    fn camera(lens: LensModel) -> CameraModel {
+        CameraModel::from_intrinsics(620.0, 580.0, 230.0, 170.0, array![400.0, 400.0]).with_lens(lens)
+    }
+
+    #[test]
+    fn distort_then_undistort_round_trips() {
+        for lens in lens_models() {
+            let camera = camera(lens.clone());
+            for i in 0..=8 {
+                for j in 0..=8 {
+                    let point = array![50.0 * i as f64, 50.0 * j as f64];
+                    let undistorted = camera.undistort_point(&camera.distort_point(&point));
+                    let redistorted = camera.distort_point(&camera.undistort_point(&point));
+                    for back in [undistorted, redistorted] {
+                        let offset = &back - &point;
+                        assert!(offset.dot(&offset).sqrt() < 1e-6, "{:?} maps {} back to {}", lens, point, back);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ellipses_round_trip_through_the_internal_frame() {
+        for lens in lens_models() {
+            let camera = camera(lens.clone());
+            let ellipse = Ellipse::new(array![260.0, 120.0], 18.0, 25.0, 0.6);
+            let back = camera.internal_ellipse_to_image(&camera.image_ellipse_to_internal(&ellipse));
+            let offset = &back.center - &ellipse.center;
+            assert!(offset.dot(&offset).sqrt() < 0.01, "{:?} moves the center to {}", lens, back.center);
+            assert!((back.minor_radius - ellipse.minor_radius).abs() < 0.01);
+            assert!((back.major_radius - ellipse.major_radius).abs() < 0.01);
+        }
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rs3d_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn intrinsics_round_trip_through_files() {
+        let dir = temp_dir("intrinsics_round_trip");
+        for file_name in ["eye0.intrinsics", "eye0.json"] {
+            let path = dir.join(file_name);
+            for lens in lens_models() {
+                let saved = camera(lens);
+                saved.save_intrinsics(&path).unwrap();
+                // A second resolution in the same file must not replace the first one
+                saved.resized(array![192.0, 192.0]).save_intrinsics(&path).unwrap();
+
+                let loaded = CameraModel::load_intrinsics(&path, array![400.0, 400.0]).unwrap();
+                assert_eq!((loaded.fx, loaded.fy, loaded.cx, loaded.cy), (saved.fx, saved.fy, saved.cx, saved.cy));
+                assert_eq!(loaded.lens, saved.lens);
+                let binned = CameraModel::load_intrinsics(&path, array![192.0, 192.0]).unwrap();
+                assert!((binned.fx - saved.fx * 0.48).abs() < 1e-9);
+            }
+        }
+
+        let missing = CameraModel::load_intrinsics(&dir.join("eye0.json"), array![640.0, 480.0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(missing, Err(DetectorError::InvalidCamera(_))));
+    }
+
+    #[test]
+    fn loads_pupil_capture_json() {
+        let dir = temp_dir("pupil_capture_json");
+        let path = dir.join("world.intrinsics.json");
+        std::fs::write(&path, r#"{
+            "version": 1,
+            "(1280, 720)": {
+                "camera_matrix": [[829.3510515270362, 0.0, 659.9293047259697], [0.0, 799.5709408845464, 373.0776462356668], [0.0, 0.0, 1.0]],
+                "dist_coefs": [[-0.43738542863224966, 0.190570781428104, -0.00125233833830639, 0.0018723428760170056, -0.039219091259637684]],
+                "resolution": [1280, 720],
+                "cam_type": "radial"
+            }
+        }"#).unwrap();
+
+        let camera = CameraModel::load_intrinsics(&path, array![1280.0, 720.0]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let camera = camera.unwrap();
+        // serde_json may round the last digit
+        assert!((camera.fx - 829.3510515270362).abs() < 1e-9);
+        assert!((camera.cy - 373.0776462356668).abs() < 1e-9);
+        match camera.lens {
+            LensModel::RadialTangential(distortion) => {
+                assert!((distortion.k1 + 0.43738542863224966).abs() < 1e-12);
+                assert!((distortion.k3 + 0.039219091259637684).abs() < 1e-12);
+                assert_eq!(distortion.k4, 0.0);
+            }
+            lens => panic!("expected radial distortion, got {:?}", lens)
+        }
+    }
+}
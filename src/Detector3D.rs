@@ -1,12 +1,17 @@
-use std::collections::HashMap;
 use std::f64::consts::PI;
 use std::option::Option;
-use ndarray::{array, Array1, ArrayD};
+use std::sync::Arc;
+use ndarray::{array, concatenate, Array1, ArrayD, Axis, s};
+use crate::background::{BackgroundEstimator, EstimationJob, ModelKind};
 use crate::CameraModel::CameraModel;
+use crate::error::DetectorError;
+use crate::eye_parameters::EyeParameters;
 use crate::kalman::KalmanFilter;
 use crate::observations::{BinBufferedObservationStorage, BufferedObservationStorage, Observation};
-use crate::primitive::Ellipse;
+use crate::primitive::{Ellipse, Sphere};
+use crate::projections::{Circle3D, project_circle_into_image_plane, project_sphere_into_image_plane};
 use crate::two_sphere_model::TwoSphereModel;
+use crate::utils::sph2cart;
 
 #[derive(PartialEq)]
 pub enum DetectorMode {
@@ -23,7 +28,8 @@ struct ModelUpdateSchedule {
 }
 
 pub struct Detector3D {
-    pub camera: CameraModel,
+    pub camera: Arc<CameraModel>,
+    pub eye_parameters: EyeParameters,
     pub threshold_swirski: f64,
     pub threshold_kalman: f64,
     pub threshold_short_term: f64,
@@ -40,9 +46,12 @@ pub struct Detector3D {
     pub short_term_model: Option<TwoSphereModel>,
     pub long_term_model: Option<TwoSphereModel>,
     pub ultra_long_term_model: Option<TwoSphereModel>,
+    pub model_id: usize,
+    pub model_birth_timestamp: Option<f64>,
 
     long_term_schedule: Option<ModelUpdateSchedule>,
-    ult_long_term_schedule: Option<ModelUpdateSchedule>
+    ult_long_term_schedule: Option<ModelUpdateSchedule>,
+    background_estimator: Option<BackgroundEstimator>
 }
 
 pub struct PupilEllipse {
@@ -57,6 +66,57 @@ pub struct PupilDatum {
     pub ellipse: PupilEllipse
 }
 
+pub struct Detector3DResult {
+    pub timestamp: f64,
+    pub sphere: Sphere,
+    pub projected_sphere: PupilEllipse,
+    pub circle_3d: Circle3D,
+    pub diameter_3d: f64,
+    pub ellipse: PupilEllipse,
+    pub location: Array1<f64>,
+    pub confidence: f64,
+    pub model_confidence: f64,
+    pub theta: f64,
+    pub phi: f64,
+    pub model_id: usize,
+    pub model_birth_timestamp: f64,
+    // Only with camera extrinsics
+    pub head_frame: Option<HeadFrameResult>
+}
+
+// The 3D part of a result in the head frame of the camera extrinsics
+pub struct HeadFrameResult {
+    pub sphere: Sphere,
+    pub circle_3d: Circle3D,
+    pub theta: f64,
+    pub phi: f64
+}
+
+impl PupilEllipse {
+    // Ellipse in pixel coordinates with axes as diameters and the angle in degrees, like the 2D detector
+    pub fn from_ellipse(ellipse: &Ellipse) -> PupilEllipse {
+        PupilEllipse {
+            center: ellipse.center.clone(),
+            axes: array!(2.0 * ellipse.minor_radius, 2.0 * ellipse.major_radius),
+            angle: ellipse.angle * 180.0 / PI + 90.0
+        }
+    }
+
+    // Conversions to other frames only keep the axis lengths, so negative axes have to be caught here
+    pub fn is_valid(&self) -> bool {
+        self.center.iter().all(|x| x.is_finite())
+            && self.axes.iter().all(|&axis| axis.is_finite() && axis > 0.0)
+            && self.angle.is_finite()
+    }
+
+    pub fn to_ellipse(&self) -> Ellipse {
+        let minor_radius = self.axes[0] / 2.0;
+        let major_radius = self.axes[1] / 2.0;
+        let angle = (self.angle - 90.0) * PI / 180.0;
+        Ellipse::new(self.center.clone(), minor_radius, major_radius, angle)
+    }
+}
+
 impl ModelUpdateSchedule {
     pub fn new(update_interval: f64, warmup_duration: f64) -> ModelUpdateSchedule {
         ModelUpdateSchedule {
@@ -84,14 +144,14 @@ impl ModelUpdateSchedule {
         if self.paused {
             return false
         }
-        if self.warmup_start == None {
+        if self.warmup_start.is_none() {
             self.warmup_start = Some(current_time);
             return true;
         }
         if current_time - self.warmup_start.unwrap() < self.warmup_duration {
             return true;
         }
-        if self.last_update == None {
+        if self.last_update.is_none() {
             self.last_update = Some(current_time);
             return true;
         }
@@ -99,7 +159,7 @@ impl ModelUpdateSchedule {
             self.last_update = Some(current_time);
             return true;
         }
-        return false;
+        false
     }
 }
 
@@ -107,10 +167,12 @@ impl Detector3D {
     pub fn new(
         camera: CameraModel,
         long_term_mode: Option<DetectorMode>,
-        calculate_rms_residual: Option<bool>
-    ) -> Detector3D {
+        calculate_rms_residual: Option<bool>,
+        eye_parameters: Option<EyeParameters>
+    ) -> Result<Detector3D, DetectorError> {
         let mut detector = Detector3D {
-            camera,
+            camera: Arc::new(camera),
+            eye_parameters: eye_parameters.unwrap_or_default(),
             threshold_swirski: 0.7,
             threshold_kalman: 0.98,
             threshold_short_term: 0.8,
@@ -127,29 +189,34 @@ impl Detector3D {
             short_term_model: None,
             long_term_model: None,
             ultra_long_term_model: None,
+            model_id: 0,
+            model_birth_timestamp: None,
             long_term_schedule: None,
-            ult_long_term_schedule: None
+            ult_long_term_schedule: None,
+            background_estimator: None
         };
 
-        detector.reset();
+        detector.reset()?;
 
-        detector
+        Ok(detector)
     }
 
     pub fn get_camera(&self) -> &CameraModel {
-        return &self.camera;
+        &self.camera
     }
 
     pub fn get_long_term_mode(&self) -> &DetectorMode {
-        return &self.long_term_mode;
+        &self.long_term_mode
     }
 
-    pub fn set_long_term_mode(&mut self, mode: DetectorMode) {
+    pub fn set_long_term_mode(&mut self, mode: DetectorMode) -> Result<(), DetectorError> {
         let needs_reset = mode != self.long_term_mode;
         self.long_term_mode = mode;
         if needs_reset {
-            self.reset();
+            self.reset()?;
         }
+
+        Ok(())
     }
 
     pub fn get_is_long_term_model_frozen(&self) -> bool {
@@ -172,95 +239,509 @@ impl Detector3D {
         }
     }
 
-    pub fn reset_camera(&mut self, camera: CameraModel) {
-        self.camera = camera;
-        self.reset();
+    pub fn reset_camera(&mut self, camera: CameraModel) -> Result<(), DetectorError> {
+        self.camera = Arc::new(camera);
+        self.reset()
     }
 
-    pub fn reset(&mut self) {
-        self.initialize_models();
+    // Switches to another capture mode of the same camera without losing the fitted models, see
+    // CameraModel::resized and CameraModel::cropped. The internal frame scales with the focal length.
+    pub fn change_camera(&mut self, camera: CameraModel) -> Result<(), DetectorError> {
+        // Distortion coefficients are in normalized coordinates, so only a change of lens invalidates them
+        if camera.lens != self.camera.lens {
+            return Err(DetectorError::InvalidCamera(format!("cannot change lens model {:?} to {:?}", self.camera.lens, camera.lens)));
+        }
+        let scale = camera.focal_length() / self.camera.focal_length();
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(DetectorError::InvalidCamera(format!("cannot rescale focal length {} to {}", self.camera.focal_length(), camera.focal_length())));
+        }
+
+        self.camera = Arc::new(camera);
+        for model in [&mut self.short_term_model, &mut self.long_term_model, &mut self.ultra_long_term_model] {
+            if let Some(model) = model.as_mut() {
+                model.change_camera(self.camera.clone(), scale)?;
+            }
+        }
+
+        // Fits that are still running use observations in the old frame
+        if let Some(background_estimator) = self.background_estimator.as_mut() {
+            background_estimator.invalidate();
+        }
+
+        Ok(())
+    }
+
+    pub fn reset_eye_parameters(&mut self, eye_parameters: EyeParameters) -> Result<(), DetectorError> {
+        self.eye_parameters = eye_parameters;
+        self.reset()
+    }
+
+    pub fn reset(&mut self) -> Result<(), DetectorError> {
+        self.initialize_models()?;
+        self.model_id += 1;
+        self.model_birth_timestamp = None;
 
         self.long_term_schedule = Some(ModelUpdateSchedule::new(self.model_update_interval_long_term, self.model_warmup_duration));
         self.ult_long_term_schedule = Some(ModelUpdateSchedule::new(self.model_update_interval_ult_long_term, self.model_warmup_duration));
 
-        self.kalman_filter = Option::from(KalmanFilter::new())
+        // Fits that belong to the old models are discarded without waiting for them
+        self.background_estimator = match self.long_term_mode {
+            DetectorMode::Blocking => None,
+            DetectorMode::Async => {
+                let mut background_estimator = self.background_estimator.take().unwrap_or_default();
+                background_estimator.invalidate();
+                Some(background_estimator)
+            }
+        };
+
+        self.kalman_filter = Some(KalmanFilter::new());
+
+        Ok(())
     }
 
-    fn initialize_models(&mut self) {
+    fn initialize_models(&mut self) -> Result<(), DetectorError> {
         self.short_term_model = Some(
             TwoSphereModel::new(
-                &self.camera,
+                self.camera.clone(),
+                self.eye_parameters.clone(),
                 Box::new(
                     BufferedObservationStorage::new(
                         self.threshold_short_term,
                         10
                     )
                 )
-            )
+            )?
         );
 
         self.long_term_model = Some(
             TwoSphereModel::new(
-                &self.camera,
+                self.camera.clone(),
+                self.eye_parameters.clone(),
                 Box::new(
                     BinBufferedObservationStorage::new(
-                        &self.camera,
+                        self.camera.clone(),
                         self.threshold_long_term,
                         10,
                         self.long_term_buffer_size,
                         Some(self.long_term_forget_observations),
                         Some(self.long_term_forget_time)
-                    )
+                    )?
                 )
-            )
+            )?
         );
 
         self.ultra_long_term_model = Some(
             TwoSphereModel::new(
-                &self.camera,
+                self.camera.clone(),
+                self.eye_parameters.clone(),
                 Box::new(
                     BinBufferedObservationStorage::new(
-                        &self.camera,
+                        self.camera.clone(),
                         self.threshold_long_term,
                         10,
                         self.long_term_buffer_size,
                         Some(2 * self.long_term_forget_observations),
                         Some(60)
-                    )
+                    )?
                 )
-            )
-        )
+            )?
+        );
+
+        Ok(())
     }
 
-    pub fn update_and_detect(&mut self, pupil_datum: PupilDatum, frame: ArrayD<f64>, apply_refraction_correction: bool, debug: bool) {
+    pub fn update_and_detect(&mut self, pupil_datum: PupilDatum, _frame: ArrayD<f64>, apply_refraction_correction: bool, _debug: bool) -> Result<Detector3DResult, DetectorError> {
         let observation = self.extract_observation(pupil_datum);
-        self.update_models(observation);
+        if self.model_birth_timestamp.is_none() {
+            self.model_birth_timestamp = Some(observation.timestamp);
+        }
+
+        self.update_models(observation.clone())?;
+
+        let pupil_circle = self.predict_pupil_circle(&observation);
+
+        self.prepare_result(&observation, pupil_circle, apply_refraction_correction)
     }
 
-    pub fn update_models(&mut self, observation: Observation) {
-        let long_term_schedule = &mut self.long_term_model.as_mut().unwrap();
-        long_term_schedule.add_observation(observation);
-        let ult_long_term_schedule = &mut self.ult_long_term_schedule.as_mut().unwrap();
+    pub fn update_models(&mut self, observation: Observation) -> Result<(), DetectorError> {
+        let timestamp = observation.timestamp;
+        let short_term_model = self.short_term_model.as_mut().unwrap();
+        let long_term_model = self.long_term_model.as_mut().unwrap();
+        let ultra_long_term_model = self.ultra_long_term_model.as_mut().unwrap();
+
+        short_term_model.add_observation(observation.clone());
+        long_term_model.add_observation(observation.clone());
+        ultra_long_term_model.add_observation(observation);
+
+        if let Some(background_estimator) = self.background_estimator.as_mut() {
+            for result in background_estimator.poll() {
+                match result.kind {
+                    ModelKind::LongTerm => long_term_model.apply_sphere_center_estimate(result.estimate)?,
+                    ModelKind::UltraLongTerm => ultra_long_term_model.apply_sphere_center_estimate(result.estimate)?
+                }
+            }
+        }
+
+        if long_term_model.n_observations() == 0 || short_term_model.n_observations() == 0 {
+            return Ok(());
+        }
+
+        // A fit still running in the background keeps its schedule where it is, so the next frame retries
+        let is_pending = |kind| self.background_estimator.as_ref().is_some_and(|background_estimator| background_estimator.is_pending(kind));
+        let ult_long_term_pending = is_pending(ModelKind::UltraLongTerm);
+        let long_term_pending = is_pending(ModelKind::LongTerm);
+
+        if !ult_long_term_pending && self.ult_long_term_schedule.as_mut().unwrap().update_due(timestamp) && ultra_long_term_model.n_observations() > 0 {
+            match self.background_estimator.as_mut() {
+                Some(background_estimator) => {
+                    background_estimator.submit(EstimationJob {
+                        kind: ModelKind::UltraLongTerm,
+                        lines: ultra_long_term_model.fit_lines(),
+                        from_2d: None,
+                        prior_3d: None,
+                        prior_strength: 0.0,
+                        calculate_rms_residual: self.calculate_rms_residual
+                    });
+                }
+                None => ultra_long_term_model.estimate_sphere_center(None, None, 0.0, self.calculate_rms_residual)?
+            }
+        }
+
+        // The ultra-long-term model only pulls on the long-term fit once it has a fit of its own
+        let long_term_prior = ultra_long_term_model.has_fit.then(|| ultra_long_term_model.sphere_center.clone());
+        if !long_term_pending && self.long_term_schedule.as_mut().unwrap().update_due(timestamp) {
+            match self.background_estimator.as_mut() {
+                Some(background_estimator) => {
+                    background_estimator.submit(EstimationJob {
+                        kind: ModelKind::LongTerm,
+                        lines: long_term_model.fit_lines(),
+                        from_2d: None,
+                        prior_3d: long_term_prior,
+                        prior_strength: 0.1,
+                        calculate_rms_residual: self.calculate_rms_residual
+                    });
+                }
+                None => long_term_model.estimate_sphere_center(None, long_term_prior, 0.1, self.calculate_rms_residual)?
+            }
+        }
+
+        // The short-term model is cheap and always fitted inline, against the last finished long-term fit
+        let (from_2d, prior_3d) = if long_term_model.has_fit {
+            (Some(long_term_model.projected_sphere_center.clone()), Some(long_term_model.sphere_center.clone()))
+        } else {
+            (None, None)
+        };
+        short_term_model.estimate_sphere_center(from_2d, prior_3d, 0.1, false)
     }
 
-    pub fn extract_observation(&mut self, pupil_datum: PupilDatum) -> Observation {
-        let cam_resolution = &self.camera.resolution.clone();
-        let width = cam_resolution[0];
-        let height = cam_resolution[1];
-        let center = array!(
-            pupil_datum.ellipse.center[0] - width / 2.0,
-            pupil_datum.ellipse.center[1] - height / 2.0
+    fn predict_pupil_circle(&mut self, observation: &Observation) -> Circle3D {
+        let long_term_model = self.long_term_model.as_ref().unwrap();
+        let short_term_model = self.short_term_model.as_ref().unwrap();
+        let kalman_filter = self.kalman_filter.as_mut().unwrap();
+
+        // Direction comes from the stable long-term model, size from the reactive short-term model
+        let pupil_circle_long_term = long_term_model.predict_pupil_circle(observation);
+        let pupil_circle_short_term = short_term_model.predict_pupil_circle(observation);
+        let mut pupil_circle = pupil_circle_long_term;
+        if !pupil_circle_short_term.is_null() {
+            pupil_circle.radius = pupil_circle_short_term.radius;
+        }
+
+        if !pupil_circle.is_null() && observation.confidence > self.threshold_kalman {
+            let (phi, theta, radius) = pupil_circle.spherical_representation();
+            kalman_filter.correct(observation.timestamp, phi, theta, radius);
+        }
+
+        if pupil_circle.is_null() || observation.confidence < self.threshold_swirski {
+            let (phi, theta, radius) = kalman_filter.predict(observation.timestamp);
+            let gaze_vector = sph2cart(phi, theta);
+            pupil_circle = Circle3D {
+                center: &long_term_model.sphere_center + self.eye_parameters.pupil_distance * &gaze_vector,
+                normal: gaze_vector,
+                radius
+            };
+        }
+
+        pupil_circle
+    }
+
+    fn prepare_result(&self, observation: &Observation, pupil_circle: Circle3D, apply_refraction_correction: bool) -> Result<Detector3DResult, DetectorError> {
+        let long_term_model = self.long_term_model.as_ref().unwrap();
+
+        // 2D results stay uncorrected so they overlay the image
+        let projected_sphere = project_sphere_into_image_plane(
+            &Sphere::new(long_term_model.sphere_center.clone(), self.eye_parameters.pupil_distance),
+            self.camera.focal_length()
         );
-        let minor_radius = pupil_datum.ellipse.axes[0] / 2.0;
-        let major_radius = pupil_datum.ellipse.axes[1] / 2.0;
-        let angle = (pupil_datum.ellipse.angle - 90.0) * PI / 180.0;
-        let ellipse = Ellipse::new(center, minor_radius, major_radius, angle);
+        let projected_pupil_circle = project_circle_into_image_plane(&pupil_circle, self.camera.focal_length())
+            .unwrap_or(Ellipse::new(array!(0.0, 0.0), 0.0, 0.0, 0.0));
+        let projected_sphere = self.ellipse_to_pupil_ellipse(&projected_sphere);
+        let ellipse = self.ellipse_to_pupil_ellipse(&projected_pupil_circle);
+
+        let (sphere_center, pupil_circle) = if apply_refraction_correction && !pupil_circle.is_null() {
+            let input = concatenate![
+                Axis(0),
+                long_term_model.sphere_center.view(),
+                pupil_circle.normal.view(),
+                array![pupil_circle.radius].view()
+            ].insert_axis(Axis(0));
+            let corrected = long_term_model.refractionizer.correct_pupil_circle(input)?.row(0).to_owned();
+            let gaze_vector = corrected.slice(s![..3]).to_owned();
+            let gaze_vector = &gaze_vector / gaze_vector.dot(&gaze_vector).sqrt();
+            let sphere_center = long_term_model.corrected_sphere_center.clone();
+            let pupil_circle = Circle3D {
+                center: &sphere_center + self.eye_parameters.pupil_distance * &gaze_vector,
+                normal: gaze_vector,
+                radius: corrected[3]
+            };
+            (sphere_center, pupil_circle)
+        } else if apply_refraction_correction {
+            (long_term_model.corrected_sphere_center.clone(), pupil_circle)
+        } else {
+            (long_term_model.sphere_center.clone(), pupil_circle)
+        };
+
+        let (phi, theta, _) = pupil_circle.spherical_representation();
+        let sphere = Sphere::new(sphere_center, self.eye_parameters.pupil_distance);
+        let head_frame = self.to_head_frame(&sphere, &pupil_circle);
+
+        Ok(Detector3DResult {
+            timestamp: observation.timestamp,
+            sphere,
+            projected_sphere,
+            diameter_3d: 2.0 * pupil_circle.radius,
+            circle_3d: pupil_circle,
+            location: ellipse.center.clone(),
+            ellipse,
+            confidence: observation.confidence,
+            // pye3d always reports 1.0, here it stays 0.0 until the long-term model has been fitted
+            model_confidence: if long_term_model.has_fit { 1.0 } else { 0.0 },
+            theta,
+            phi,
+            model_id: self.model_id,
+            model_birth_timestamp: self.model_birth_timestamp.unwrap_or(observation.timestamp),
+            head_frame
+        })
+    }
+
+    fn to_head_frame(&self, sphere: &Sphere, pupil_circle: &Circle3D) -> Option<HeadFrameResult> {
+        let circle_3d = if pupil_circle.is_null() {
+            Circle3D::null()
+        } else {
+            Circle3D {
+                center: self.camera.point_to_head(&pupil_circle.center)?,
+                normal: self.camera.direction_to_head(&pupil_circle.normal)?,
+                radius: pupil_circle.radius
+            }
+        };
+        let (phi, theta, _) = circle_3d.spherical_representation();
+
+        Some(HeadFrameResult {
+            sphere: Sphere::new(self.camera.point_to_head(&sphere.center)?, sphere.radius),
+            circle_3d,
+            theta,
+            phi
+        })
+    }
+
+    fn ellipse_to_pupil_ellipse(&self, ellipse: &Ellipse) -> PupilEllipse {
+        PupilEllipse::from_ellipse(&self.camera.internal_ellipse_to_image(ellipse))
+    }
+
+    pub fn extract_observation(&mut self, pupil_datum: PupilDatum) -> Observation {
+        // The ellipse stays in pixels, invalid observations are never projected
+        if !pupil_datum.ellipse.is_valid() {
+            return Observation::invalid(pupil_datum.ellipse.to_ellipse(), pupil_datum.confidence, pupil_datum.timestamp, &self.eye_parameters);
+        }
+
+        let ellipse = self.camera.image_ellipse_to_internal(&pupil_datum.ellipse.to_ellipse());
 
         Observation::new(
             ellipse,
             pupil_datum.confidence,
             pupil_datum.timestamp,
-            self.camera.focal_length
+            self.camera.focal_length(),
+            &self.eye_parameters
         )
     }
-}
\ No newline at end of file
+}
+
+// Detector3D has to stay movable into worker threads and async tasks
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Detector3D>();
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Isometry3, Point3, Vector3};
+    use ndarray::IxDyn;
+    use crate::CameraModel::{KannalaBrandtDistortion, LensModel};
+    use crate::simulator::{EyeModel, EyeSimulator};
+    use crate::utils::cart2sph;
+
+    fn camera() -> CameraModel {
+        CameraModel::new(620.0, array![400.0, 400.0])
+    }
+
+    fn gaze(i: usize) -> Array1<f64> {
+        sph2cart(-PI / 2.0 + 0.5 * (i as f64 * 0.77).sin(), PI / 2.0 + 0.4 * (i as f64 * 1.31).cos())
+    }
+
+    fn frame() -> ArrayD<f64> {
+        ArrayD::zeros(IxDyn(&[1]))
+    }
+
+    #[test]
+    fn negative_axes_are_invalid_observations() {
+        let simulator = EyeSimulator::new(Arc::new(camera()), EyeModel::le_grand(array![3.0, -2.0, 35.0]));
+        let mut detector = Detector3D::new(camera(), None, None, None).unwrap();
+        for i in 0..100 {
+            let pupil_datum = simulator.pupil_datum(&simulator.simulate(&gaze(i), 2.0).unwrap(), 0.99, i as f64 * 0.05);
+            detector.update_and_detect(pupil_datum, frame(), true, false).unwrap();
+        }
+        let sphere_center = detector.long_term_model.as_ref().unwrap().sphere_center.clone();
+
+        for (i, axes) in [array![-20.0, 30.0], array![20.0, f64::NAN], array![0.0, 30.0]].into_iter().enumerate() {
+            let pupil_datum = PupilDatum {
+                confidence: 0.99,
+                timestamp: 5.0 + i as f64,
+                ellipse: PupilEllipse {
+                    center: array![180.0, 210.0],
+                    axes,
+                    angle: 30.0
+                }
+            };
+            let result = detector.update_and_detect(pupil_datum, frame(), true, false).unwrap();
+            assert_eq!(result.confidence, 0.0);
+        }
+
+        assert_eq!(detector.long_term_model.as_ref().unwrap().sphere_center, sphere_center);
+    }
+
+    #[test]
+    fn async_fits_are_applied_and_dropped_on_reset() {
+        let sphere_center = array![3.0, -2.0, 40.0];
+        let simulator = EyeSimulator::new(Arc::new(camera()), EyeModel::le_grand(sphere_center.clone()));
+        let mut detector = Detector3D::new(camera(), Some(DetectorMode::Async), None, Some(simulator.eye.parameters.clone())).unwrap();
+
+        // Give the worker time to finish so every scheduled fit lands a frame or two later
+        let mut error = f64::INFINITY;
+        let mut model_confidence = 0.0;
+        for i in 0..400 {
+            let pupil_datum = simulator.pupil_datum(&simulator.simulate(&gaze(i), 2.0).unwrap(), 0.99, i as f64 * 0.05);
+            let result = detector.update_and_detect(pupil_datum, frame(), true, false).unwrap();
+            // The first fit is still running
+            if i == 0 {
+                assert_eq!(result.model_confidence, 0.0);
+            }
+            let offset = &result.sphere.center - &sphere_center;
+            error = offset.dot(&offset).sqrt();
+            model_confidence = result.model_confidence;
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert_eq!(model_confidence, 1.0);
+        assert!(detector.long_term_model.as_ref().unwrap().has_fit);
+        assert!(detector.ultra_long_term_model.as_ref().unwrap().has_fit);
+        assert!(error < 0.6, "sphere center off by {} mm", error);
+
+        // A fit of the old observations that finishes after the reset must not reach the new models
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        detector.background_estimator.as_mut().unwrap().poll();
+        let stale_job = EstimationJob {
+            kind: ModelKind::LongTerm,
+            lines: detector.long_term_model.as_ref().unwrap().fit_lines(),
+            from_2d: None,
+            prior_3d: None,
+            prior_strength: 0.0,
+            calculate_rms_residual: false
+        };
+        assert!(detector.background_estimator.as_mut().unwrap().submit(stale_job));
+        detector.reset().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let pupil_datum = simulator.pupil_datum(&simulator.simulate(&gaze(0), 2.0).unwrap(), 0.99, 100.0);
+        detector.update_and_detect(pupil_datum, frame(), true, false).unwrap();
+        let long_term_model = detector.long_term_model.as_ref().unwrap();
+        assert_eq!(long_term_model.n_observations(), 1);
+        assert!(!long_term_model.has_fit);
+    }
+
+    #[test]
+    fn change_camera_keeps_models_across_capture_modes() {
+        let sphere_center = array![3.0, -2.0, 40.0];
+        let mut detector = Detector3D::new(camera(), None, None, Some(EyeModel::le_grand(sphere_center.clone()).parameters)).unwrap();
+        let mut timestamp = 0.0;
+        let mut run = |detector: &mut Detector3D, camera: CameraModel, frames: usize| {
+            let simulator = EyeSimulator::new(Arc::new(camera), EyeModel::le_grand(sphere_center.clone()));
+            let mut max_error: f64 = 0.0;
+            for i in 0..frames {
+                timestamp += 0.05;
+                let pupil_datum = simulator.pupil_datum(&simulator.simulate(&gaze(i), 2.0).unwrap(), 0.99, timestamp);
+                let result = detector.update_and_detect(pupil_datum, frame(), true, false).unwrap();
+                let offset = &result.sphere.center - &sphere_center;
+                max_error = max_error.max(offset.dot(&offset).sqrt());
+            }
+            max_error
+        };
+        run(&mut detector, camera(), 200);
+
+        let capture_modes: [fn() -> CameraModel; 2] = [
+            || camera().resized(array![192.0, 192.0]),
+            || camera().cropped(40.0, 60.0, array![320.0, 320.0])
+        ];
+        let n_observations = |detector: &Detector3D| [&detector.short_term_model, &detector.long_term_model, &detector.ultra_long_term_model]
+            .map(|model| model.as_ref().unwrap().n_observations());
+        for capture_mode in capture_modes {
+            let before = n_observations(&detector);
+            let sphere_center_before = detector.long_term_model.as_ref().unwrap().sphere_center.clone();
+
+            detector.change_camera(capture_mode()).unwrap();
+            assert_eq!(n_observations(&detector), before);
+            assert_eq!(detector.long_term_model.as_ref().unwrap().sphere_center, sphere_center_before);
+
+            let max_error = run(&mut detector, capture_mode(), 100);
+            assert!(max_error < 0.6, "sphere center off by {} mm", max_error);
+            detector.change_camera(camera()).unwrap();
+        }
+
+        let fisheye = camera().with_lens(LensModel::KannalaBrandt(KannalaBrandtDistortion::default()));
+        assert!(matches!(detector.change_camera(fisheye), Err(DetectorError::InvalidCamera(_))));
+    }
+
+    #[test]
+    fn head_frame_result_is_the_transformed_camera_frame_result() {
+        let extrinsics = Isometry3::new(Vector3::new(10.0, -5.0, 30.0), Vector3::new(0.3, -0.2, 0.5));
+        let simulator = EyeSimulator::new(Arc::new(camera()), EyeModel::le_grand(array![3.0, -2.0, 35.0]));
+        let mut detector = Detector3D::new(camera(), None, None, None).unwrap();
+        let mut head_detector = Detector3D::new(camera().with_extrinsics(extrinsics), None, None, None).unwrap();
+
+        let point_to_head = |p: &Array1<f64>| {
+            let p = extrinsics * Point3::new(p[0], p[1], p[2]);
+            array![p.x, p.y, p.z]
+        };
+        let direction_to_head = |v: &Array1<f64>| {
+            let v = extrinsics.rotation * Vector3::new(v[0], v[1], v[2]);
+            array![v.x, v.y, v.z]
+        };
+        let assert_close = |a: &Array1<f64>, b: &Array1<f64>| assert!((a - b).iter().all(|d| d.abs() < 1e-9), "{} != {}", a, b);
+
+        for i in 0..100 {
+            let pupil_datum = || simulator.pupil_datum(&simulator.simulate(&gaze(i), 2.0).unwrap(), 0.99, i as f64 * 0.05);
+            let result = detector.update_and_detect(pupil_datum(), frame(), true, false).unwrap();
+            let head_result = head_detector.update_and_detect(pupil_datum(), frame(), true, false).unwrap();
+            assert!(result.head_frame.is_none());
+
+            let head_frame = head_result.head_frame.unwrap();
+            assert_close(&head_frame.sphere.center, &point_to_head(&result.sphere.center));
+            assert_eq!(head_frame.sphere.radius, result.sphere.radius);
+            assert_close(&head_frame.circle_3d.center, &point_to_head(&result.circle_3d.center));
+            assert_close(&head_frame.circle_3d.normal, &direction_to_head(&result.circle_3d.normal));
+            assert_eq!(head_frame.circle_3d.radius, result.circle_3d.radius);
+
+            let (phi, theta) = cart2sph(direction_to_head(&result.circle_3d.normal));
+            assert!((head_frame.phi - phi).abs() < 1e-9 && (head_frame.theta - theta).abs() < 1e-9);
+        }
+    }
+}
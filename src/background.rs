@@ -0,0 +1,116 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use ndarray::Array1;
+use crate::observations::FitLines;
+use crate::two_sphere_model::{SphereCenterEstimate, TwoSphereModel};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ModelKind {
+    LongTerm,
+    UltraLongTerm
+}
+
+pub struct EstimationJob {
+    pub kind: ModelKind,
+    pub lines: Vec<FitLines>,
+    pub from_2d: Option<Array1<f64>>,
+    pub prior_3d: Option<Array1<f64>>,
+    pub prior_strength: f64,
+    pub calculate_rms_residual: bool
+}
+
+pub struct EstimationResult {
+    pub kind: ModelKind,
+    pub estimate: SphereCenterEstimate
+}
+
+// The worker is detached, it ends on its own once the job channel closes. Jobs and results are
+// tagged with the generation they were submitted in so fits for discarded models can be dropped.
+pub struct BackgroundEstimator {
+    jobs: Sender<(u64, EstimationJob)>,
+    results: Receiver<(u64, EstimationResult)>,
+    generation: u64,
+    long_term_pending: bool,
+    ult_long_term_pending: bool
+}
+
+impl BackgroundEstimator {
+    pub fn new() -> BackgroundEstimator {
+        let (job_sender, job_receiver) = channel::<(u64, EstimationJob)>();
+        let (result_sender, result_receiver) = channel();
+
+        std::thread::spawn(move || {
+            for (generation, job) in job_receiver {
+                let estimate = TwoSphereModel::fit_sphere_center(
+                    &job.lines,
+                    job.from_2d,
+                    job.prior_3d,
+                    job.prior_strength,
+                    job.calculate_rms_residual
+                );
+                if result_sender.send((generation, EstimationResult { kind: job.kind, estimate })).is_err() {
+                    break;
+                }
+            }
+        });
+
+        BackgroundEstimator {
+            jobs: job_sender,
+            results: result_receiver,
+            generation: 0,
+            long_term_pending: false,
+            ult_long_term_pending: false
+        }
+    }
+
+    pub fn is_pending(&self, kind: ModelKind) -> bool {
+        match kind {
+            ModelKind::LongTerm => self.long_term_pending,
+            ModelKind::UltraLongTerm => self.ult_long_term_pending
+        }
+    }
+
+    fn set_pending(&mut self, kind: ModelKind, pending: bool) {
+        match kind {
+            ModelKind::LongTerm => self.long_term_pending = pending,
+            ModelKind::UltraLongTerm => self.ult_long_term_pending = pending
+        }
+    }
+
+    // Returns false if a fit of the same kind is still running, so the queue never backs up
+    pub fn submit(&mut self, job: EstimationJob) -> bool {
+        if self.is_pending(job.kind) {
+            return false;
+        }
+
+        let kind = job.kind;
+        let submitted = self.jobs.send((self.generation, job)).is_ok();
+        self.set_pending(kind, submitted);
+
+        submitted
+    }
+
+    // Fits submitted before this are discarded when they finish, without waiting for them
+    pub fn invalidate(&mut self) {
+        self.generation += 1;
+        self.long_term_pending = false;
+        self.ult_long_term_pending = false;
+    }
+
+    pub fn poll(&mut self) -> Vec<EstimationResult> {
+        let results: Vec<EstimationResult> = self.results.try_iter()
+            .filter(|(generation, _)| *generation == self.generation)
+            .map(|(_, result)| result)
+            .collect();
+        for result in &results {
+            self.set_pending(result.kind, false);
+        }
+
+        results
+    }
+}
+
+impl Default for BackgroundEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+use nalgebra::{Rotation3, Vector3};
+use ndarray::array;
+use opencv::calib3d;
+use opencv::core::{Mat, Point2f, Point3f, Size, TermCriteria, TermCriteria_Type, Vector};
+use opencv::imgcodecs;
+use opencv::imgproc;
+use opencv::objdetect;
+use opencv::prelude::*;
+use crate::CameraModel::CameraModel;
+use crate::error::DetectorError;
+
+// Sizes count inner corners for checkerboards and squares for ChArUco boards, lengths are in mm
+pub enum CalibrationPattern {
+    Checkerboard {
+        columns: i32,
+        rows: i32,
+        square_size: f32
+    },
+    Charuco {
+        columns: i32,
+        rows: i32,
+        square_size: f32,
+        marker_size: f32,
+        dictionary: objdetect::PredefinedDictionaryType
+    }
+}
+
+pub struct ImageReprojectionError {
+    pub path: PathBuf,
+    pub rms_error: f64
+}
+
+pub struct CalibrationResult {
+    pub camera: CameraModel,
+    pub rms_error: f64,
+    pub per_image: Vec<ImageReprojectionError>,
+    pub skipped: Vec<PathBuf>
+}
+
+struct Detection {
+    path: PathBuf,
+    object_points: Vector<Point3f>,
+    image_points: Vector<Point2f>
+}
+
+// A board needs at least this many corners to constrain its pose
+const MIN_CORNERS_PER_IMAGE: usize = 6;
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "bmp", "tif", "tiff"];
+
+pub fn calibrate_directory(dir: &Path, pattern: &CalibrationPattern, fisheye: bool) -> Result<CalibrationResult, DetectorError> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str())))
+        .collect();
+    paths.sort();
+
+    let mut detections = Vec::new();
+    let mut skipped = Vec::new();
+    let mut image_size: Option<Size> = None;
+    for path in paths {
+        let image = imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_GRAYSCALE)?;
+        if image.empty() {
+            skipped.push(path);
+            continue;
+        }
+
+        // All images have to come from the same camera mode
+        let size = image.size()?;
+        match image_size {
+            Some(image_size) if image_size != size => {
+                return Err(DetectorError::Calibration(format!(
+                    "{} is {}x{}, expected {}x{}", path.display(), size.width, size.height, image_size.width, image_size.height
+                )));
+            }
+            _ => image_size = Some(size)
+        }
+
+        match detect_pattern(&image, pattern)? {
+            Some((object_points, image_points)) => detections.push(Detection { path, object_points, image_points }),
+            None => skipped.push(path)
+        }
+    }
+
+    let image_size = match image_size {
+        Some(image_size) if detections.len() >= 3 => image_size,
+        _ => return Err(DetectorError::Calibration(format!("found the pattern in {} images, need at least 3", detections.len())))
+    };
+
+    let object_points: Vector<Vector<Point3f>> = detections.iter().map(|detection| detection.object_points.clone()).collect();
+    let image_points: Vector<Vector<Point2f>> = detections.iter().map(|detection| detection.image_points.clone()).collect();
+    let mut camera_matrix = Mat::default();
+    let mut dist_coefs = Mat::default();
+    let mut rvecs = Vector::<Mat>::new();
+    let mut tvecs = Vector::<Mat>::new();
+    let criteria = TermCriteria::new(TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32, 100, f64::EPSILON)?;
+
+    let rms_error = if fisheye {
+        calib3d::fisheye_calibrate(
+            &object_points,
+            &image_points,
+            image_size,
+            &mut camera_matrix,
+            &mut dist_coefs,
+            &mut rvecs,
+            &mut tvecs,
+            calib3d::fisheye_CALIB_RECOMPUTE_EXTRINSIC | calib3d::fisheye_CALIB_FIX_SKEW,
+            criteria
+        )?
+    } else {
+        calib3d::calibrate_camera(
+            &object_points,
+            &image_points,
+            image_size,
+            &mut camera_matrix,
+            &mut dist_coefs,
+            &mut rvecs,
+            &mut tvecs,
+            0,
+            criteria
+        )?
+    };
+
+    let dist_coefs: Vec<f64> = (0..dist_coefs.total() as i32)
+        .map(|i| dist_coefs.at::<f64>(i).copied())
+        .collect::<Result<_, _>>()?;
+    let camera = CameraModel::from_intrinsics(
+        *camera_matrix.at_2d::<f64>(0, 0)?,
+        *camera_matrix.at_2d::<f64>(1, 1)?,
+        *camera_matrix.at_2d::<f64>(0, 2)?,
+        *camera_matrix.at_2d::<f64>(1, 2)?,
+        array![image_size.width as f64, image_size.height as f64]
+    );
+    let camera = if fisheye {
+        camera.with_fisheye_distortion(&dist_coefs)
+    } else {
+        camera.with_distortion(&dist_coefs)
+    };
+
+    // Reproject through our own camera model, which also checks that it agrees with OpenCV
+    let mut per_image = Vec::with_capacity(detections.len());
+    for (i, detection) in detections.into_iter().enumerate() {
+        let rvec = rvecs.get(i)?;
+        let tvec = tvecs.get(i)?;
+        let rotation = Rotation3::from_scaled_axis(Vector3::new(*rvec.at::<f64>(0)?, *rvec.at::<f64>(1)?, *rvec.at::<f64>(2)?));
+        let translation = Vector3::new(*tvec.at::<f64>(0)?, *tvec.at::<f64>(1)?, *tvec.at::<f64>(2)?);
+
+        let mut sum_squared_error = 0.0;
+        for (object_point, image_point) in detection.object_points.iter().zip(detection.image_points.iter()) {
+            let point = rotation * Vector3::new(object_point.x as f64, object_point.y as f64, object_point.z as f64) + translation;
+            let projected = camera.project_point(&array![point.x, point.y, point.z]);
+            sum_squared_error += (projected[0] - image_point.x as f64).powi(2) + (projected[1] - image_point.y as f64).powi(2);
+        }
+
+        per_image.push(ImageReprojectionError {
+            path: detection.path,
+            rms_error: (sum_squared_error / detection.image_points.len() as f64).sqrt()
+        });
+    }
+
+    Ok(CalibrationResult {
+        camera,
+        rms_error,
+        per_image,
+        skipped
+    })
+}
+
+fn detect_pattern(image: &Mat, pattern: &CalibrationPattern) -> Result<Option<(Vector<Point3f>, Vector<Point2f>)>, DetectorError> {
+    match pattern {
+        CalibrationPattern::Checkerboard { columns, rows, square_size } => {
+            let mut corners = Vector::<Point2f>::new();
+            let found = calib3d::find_chessboard_corners(
+                image,
+                Size::new(*columns, *rows),
+                &mut corners,
+                calib3d::CALIB_CB_ADAPTIVE_THRESH | calib3d::CALIB_CB_NORMALIZE_IMAGE
+            )?;
+            if !found {
+                return Ok(None);
+            }
+
+            let criteria = TermCriteria::new(TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32, 30, 1e-3)?;
+            imgproc::corner_sub_pix(image, &mut corners, Size::new(11, 11), Size::new(-1, -1), criteria)?;
+
+            let object_points: Vector<Point3f> = (0..*rows)
+                .flat_map(|row| (0..*columns).map(move |column| Point3f::new(column as f32 * square_size, row as f32 * square_size, 0.0)))
+                .collect();
+
+            Ok(Some((object_points, corners)))
+        }
+        CalibrationPattern::Charuco { columns, rows, square_size, marker_size, dictionary } => {
+            let dictionary = objdetect::get_predefined_dictionary(*dictionary)?;
+            let board = objdetect::CharucoBoard::new_def(Size::new(*columns, *rows), *square_size, *marker_size, &dictionary)?;
+            let detector = objdetect::CharucoDetector::new_def(&board)?;
+
+            let mut charuco_corners = Mat::default();
+            let mut charuco_ids = Mat::default();
+            detector.detect_board_def(image, &mut charuco_corners, &mut charuco_ids)?;
+            if (charuco_ids.total() as usize) < MIN_CORNERS_PER_IMAGE {
+                return Ok(None);
+            }
+
+            let mut object_points = Vector::<Point3f>::new();
+            let mut image_points = Vector::<Point2f>::new();
+            board.match_image_points(&charuco_corners, &charuco_ids, &mut object_points, &mut image_points)?;
+
+            Ok(Some((object_points, image_points)))
+        }
+    }
+}
+
+pub fn parse_dictionary(name: &str) -> Option<objdetect::PredefinedDictionaryType> {
+    use objdetect::PredefinedDictionaryType::*;
+    match name.to_uppercase().as_str() {
+        "4X4_50" => Some(DICT_4X4_50),
+        "4X4_100" => Some(DICT_4X4_100),
+        "4X4_250" => Some(DICT_4X4_250),
+        "4X4_1000" => Some(DICT_4X4_1000),
+        "5X5_50" => Some(DICT_5X5_50),
+        "5X5_100" => Some(DICT_5X5_100),
+        "5X5_250" => Some(DICT_5X5_250),
+        "5X5_1000" => Some(DICT_5X5_1000),
+        "6X6_50" => Some(DICT_6X6_50),
+        "6X6_100" => Some(DICT_6X6_100),
+        "6X6_250" => Some(DICT_6X6_250),
+        "6X6_1000" => Some(DICT_6X6_1000),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Point, Scalar, CV_8UC1};
+
+    const FX: f64 = 600.0;
+    const FY: f64 = 590.0;
+    const CX: f64 = 322.0;
+    const CY: f64 = 236.0;
+
+    // Renders a board with columns x rows inner corners seen from a pinhole camera, which maps every square to
+    // a quadrilateral, so filled polygons give an exact image of the board
+    fn render_checkerboard(columns: i32, rows: i32, square_size: f64, rotation: &Rotation3<f64>, translation: &Vector3<f64>) -> Result<Mat, DetectorError> {
+        let mut image = Mat::new_rows_cols_with_default(480, 640, CV_8UC1, Scalar::all(255.0))?;
+        let project = |x: f64, y: f64| {
+            let point = rotation * Vector3::new(x, y, 0.0) + translation;
+            // Sub-pixel polygon corners with 4 fractional bits
+            Point::new((16.0 * (FX * point.x / point.z + CX)).round() as i32, (16.0 * (FY * point.y / point.z + CY)).round() as i32)
+        };
+
+        // Square (row, column) ends at inner corner (row, column), so there is one more square each way
+        for row in 0..=rows {
+            for column in 0..=columns {
+                if (row + column) % 2 != 0 {
+                    continue;
+                }
+                let (x, y) = ((column - 1) as f64 * square_size, (row - 1) as f64 * square_size);
+                let square: Vector<Point> = Vector::from_iter([
+                    project(x, y),
+                    project(x + square_size, y),
+                    project(x + square_size, y + square_size),
+                    project(x, y + square_size)
+                ]);
+                imgproc::fill_convex_poly(&mut image, &square, Scalar::all(0.0), imgproc::LINE_AA, 4)?;
+            }
+        }
+
+        Ok(image)
+    }
+
+    #[test]
+    fn recovers_intrinsics_of_rendered_checkerboards() {
+        let (columns, rows, square_size) = (9, 6, 20.0);
+        let dir = std::env::temp_dir().join(format!("rs3d_calibration_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let board_center = Vector3::new(0.5 * (columns - 1) as f64 * square_size, 0.5 * (rows - 1) as f64 * square_size, 0.0);
+        let tilts = [[0.3, 0.2, 0.0], [-0.3, 0.1, 0.1], [0.1, -0.35, -0.1], [0.25, 0.25, 0.05], [-0.2, -0.3, 0.0], [0.0, 0.0, 0.3]];
+        for (i, tilt) in tilts.iter().enumerate() {
+            let rotation = Rotation3::from_scaled_axis(Vector3::new(tilt[0], tilt[1], tilt[2]));
+            let translation = Vector3::new(10.0 * i as f64 - 25.0, 5.0, 400.0) - rotation * board_center;
+            let image = render_checkerboard(columns, rows, square_size, &rotation, &translation).unwrap();
+            imgcodecs::imwrite(&dir.join(format!("board_{}.png", i)).to_string_lossy(), &image, &Vector::new()).unwrap();
+        }
+
+        let pattern = CalibrationPattern::Checkerboard { columns, rows, square_size: square_size as f32 };
+        let result = calibrate_directory(&dir, &pattern, false);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let result = result.unwrap();
+
+        assert!(result.skipped.is_empty());
+        assert!(result.rms_error < 0.5, "RMS reprojection error {} px", result.rms_error);
+        let camera = result.camera;
+        assert!((camera.fx - FX).abs() < 0.01 * FX, "fx {}", camera.fx);
+        assert!((camera.fy - FY).abs() < 0.01 * FY, "fy {}", camera.fy);
+        assert!((camera.cx - CX).abs() < 3.0, "cx {}", camera.cx);
+        assert!((camera.cy - CY).abs() < 3.0, "cy {}", camera.cy);
+    }
+}
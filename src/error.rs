@@ -0,0 +1,85 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum DetectorError {
+    Io(std::io::Error),
+    Decode(rmp_serde::decode::Error),
+    Encode(rmp_serde::encode::Error),
+    Json(serde_json::Error),
+    #[cfg(feature = "opencv")]
+    OpenCv(opencv::Error),
+    ModelNotFound(String),
+    UnsupportedModelVersion(u8),
+    InvalidTrainingData(String),
+    InvalidInput(String),
+    NoSolution,
+    InvalidCamera(String),
+    Calibration(String),
+    Usage(String)
+}
+
+impl fmt::Display for DetectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetectorError::Io(e) => write!(f, "I/O error: {}", e),
+            DetectorError::Decode(e) => write!(f, "failed to decode model: {}", e),
+            DetectorError::Encode(e) => write!(f, "failed to encode model: {}", e),
+            DetectorError::Json(e) => write!(f, "JSON error: {}", e),
+            #[cfg(feature = "opencv")]
+            DetectorError::OpenCv(e) => write!(f, "OpenCV error: {}", e),
+            DetectorError::ModelNotFound(name) => write!(f, "no refraction model named {}", name),
+            DetectorError::UnsupportedModelVersion(version) => write!(f, "unsupported refraction model version {}", version),
+            DetectorError::InvalidTrainingData(reason) => write!(f, "invalid training data: {}", reason),
+            DetectorError::InvalidInput(reason) => write!(f, "invalid model input: {}", reason),
+            DetectorError::NoSolution => write!(f, "no solution"),
+            DetectorError::InvalidCamera(reason) => write!(f, "invalid camera: {}", reason),
+            DetectorError::Calibration(reason) => write!(f, "calibration failed: {}", reason),
+            DetectorError::Usage(usage) => write!(f, "{}", usage)
+        }
+    }
+}
+
+impl std::error::Error for DetectorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DetectorError::Io(e) => Some(e),
+            DetectorError::Decode(e) => Some(e),
+            DetectorError::Encode(e) => Some(e),
+            DetectorError::Json(e) => Some(e),
+            #[cfg(feature = "opencv")]
+            DetectorError::OpenCv(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for DetectorError {
+    fn from(e: std::io::Error) -> Self {
+        DetectorError::Io(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DetectorError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        DetectorError::Decode(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for DetectorError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        DetectorError::Encode(e)
+    }
+}
+
+impl From<serde_json::Error> for DetectorError {
+    fn from(e: serde_json::Error) -> Self {
+        DetectorError::Json(e)
+    }
+}
+
+#[cfg(feature = "opencv")]
+impl From<opencv::Error> for DetectorError {
+    fn from(e: opencv::Error) -> Self {
+        DetectorError::OpenCv(e)
+    }
+}
@@ -0,0 +1,125 @@
+use crate::two_sphere_model::SPHERE_RADIUS_DEFAULT;
+
+// Anatomical parameters in mm, measured from the eyeball center along the gaze direction. The sphere the
+// detector reports is the one the pupil center moves on, so its radius is pupil_distance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EyeParameters {
+    pub cornea_radius: f64,
+    pub cornea_center_distance: f64,
+    pub pupil_distance: f64,
+    pub refractive_index: f64,
+    // Refraction model sets to choose from, the one trained on the closest eye is loaded
+    pub refraction_model_sets: Vec<RefractionModelSet>
+}
+
+// The eye a refraction model set was trained on. type_, degree and custom_load_dir locate its files, see
+// Refractionizer::load_config_from_msgpack, sets without a custom_load_dir are the embedded ones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RefractionModelSet {
+    pub type_: String,
+    pub degree: i8,
+    pub custom_load_dir: Option<String>,
+    pub cornea_radius: f64,
+    pub cornea_center_distance: f64,
+    pub pupil_distance: f64,
+    pub refractive_index: f64
+}
+
+impl EyeParameters {
+    // LeGrand schematic eye, which the default refraction models were trained on
+    pub fn le_grand() -> EyeParameters {
+        let mut eye_parameters = EyeParameters {
+            cornea_radius: 7.8,
+            cornea_center_distance: 5.3,
+            pupil_distance: SPHERE_RADIUS_DEFAULT,
+            refractive_index: 1.3375,
+            refraction_model_sets: Vec::new()
+        };
+        eye_parameters.refraction_model_sets.push(RefractionModelSet::trained_on("default", 3, None, &eye_parameters));
+
+        eye_parameters
+    }
+
+    // Always uses the given model set, whatever the eye parameters are
+    pub fn with_refraction_models(mut self, type_: &str, degree: i8, custom_load_dir: Option<&str>) -> EyeParameters {
+        self.refraction_model_sets = vec![RefractionModelSet::trained_on(type_, degree, custom_load_dir, &self)];
+        self
+    }
+
+    // Adds a model set to choose from, e.g. one trained for an unusual cornea
+    pub fn with_refraction_model_set(mut self, model_set: RefractionModelSet) -> EyeParameters {
+        self.refraction_model_sets.push(model_set);
+        self
+    }
+
+    pub fn refraction_model_set(&self) -> Option<&RefractionModelSet> {
+        self.refraction_model_sets.iter()
+            .min_by(|a, b| a.distance(self).total_cmp(&b.distance(self)))
+    }
+}
+
+impl Default for EyeParameters {
+    fn default() -> Self {
+        Self::le_grand()
+    }
+}
+
+impl RefractionModelSet {
+    pub fn trained_on(type_: &str, degree: i8, custom_load_dir: Option<&str>, eye_parameters: &EyeParameters) -> RefractionModelSet {
+        RefractionModelSet {
+            type_: type_.to_owned(),
+            degree,
+            custom_load_dir: custom_load_dir.map(str::to_owned),
+            cornea_radius: eye_parameters.cornea_radius,
+            cornea_center_distance: eye_parameters.cornea_center_distance,
+            pupil_distance: eye_parameters.pupil_distance,
+            refractive_index: eye_parameters.refractive_index
+        }
+    }
+
+    // Sum of squared relative differences, so no parameter wins through its unit. Refraction scales with n - 1.
+    fn distance(&self, eye_parameters: &EyeParameters) -> f64 {
+        [
+            (self.cornea_radius, eye_parameters.cornea_radius),
+            (self.cornea_center_distance, eye_parameters.cornea_center_distance),
+            (self.pupil_distance, eye_parameters.pupil_distance),
+            (self.refractive_index - 1.0, eye_parameters.refractive_index - 1.0)
+        ].iter().map(|(trained, actual)| ((actual - trained) / trained).powi(2)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steep_cornea() -> EyeParameters {
+        EyeParameters {
+            cornea_radius: 6.9,
+            ..EyeParameters::le_grand()
+        }
+    }
+
+    #[test]
+    fn picks_the_model_set_of_the_closest_eye() {
+        let steep = RefractionModelSet::trained_on("steep", 2, Some("/models"), &steep_cornea());
+        let default = EyeParameters::le_grand().refraction_model_set().unwrap().clone();
+        assert_eq!((default.type_.as_str(), default.degree, default.custom_load_dir.as_deref()), ("default", 3, None));
+        assert_eq!(EyeParameters::le_grand().with_refraction_model_set(steep.clone()).refraction_model_set(), Some(&default));
+
+        // The embedded default set and a custom set with its own degree and directory coexist
+        let eye_parameters = EyeParameters {
+            cornea_radius: 7.1,
+            ..EyeParameters::le_grand()
+        }.with_refraction_model_set(steep.clone());
+        assert_eq!(eye_parameters.refraction_model_set(), Some(&steep));
+    }
+
+    #[test]
+    fn explicit_model_set_wins() {
+        let eye_parameters = steep_cornea().with_refraction_models("custom", 2, Some("/models"));
+        let model_set = eye_parameters.refraction_model_set().unwrap();
+        assert_eq!(model_set.type_, "custom");
+        assert_eq!(model_set.degree, 2);
+        assert_eq!(model_set.custom_load_dir.as_deref(), Some("/models"));
+    }
+}
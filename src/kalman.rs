@@ -1,84 +1,201 @@
-use opencv::prelude::*;
-use opencv::core::{CV_32F, Mat, MatExprResult};
+use nalgebra::{SMatrix, SVector};
 
+// State is (phi, theta, phi', theta', phi'', theta'', radius), constant acceleration for the angles and
+// a constant pupil radius. Matrices follow cv::KalmanFilter.
 pub struct KalmanFilter {
-    pub filter: opencv::video::KalmanFilter,
-    pub last_call: i32
+    pub state: SVector<f64, 7>,
+    pub error_cov: SMatrix<f64, 7, 7>,
+    pub transition_matrix: SMatrix<f64, 7, 7>,
+    pub measurement_matrix: SMatrix<f64, 3, 7>,
+    pub process_noise_cov: SMatrix<f64, 7, 7>,
+    pub measurement_noise_cov: SMatrix<f64, 3, 3>,
+    // Spectral densities of the white noise driving the angular jerk in rad^2/s^5 and the radius in mm^2/s
+    pub angular_jerk_noise: f64,
+    pub radius_noise: f64,
+    // Timestamp in seconds the state was last advanced to
+    pub last_call: Option<f64>
 }
 
 impl KalmanFilter {
     pub fn new() -> KalmanFilter {
-        let mut filter = opencv::video::KalmanFilter::new(7, 3, 0, CV_32F).unwrap();
+        let mut measurement_matrix = SMatrix::<f64, 3, 7>::zeros();
+        measurement_matrix[(0, 0)] = 1.0;
+        measurement_matrix[(1, 1)] = 1.0;
+        measurement_matrix[(2, 6)] = 1.0;
 
-        let slice: [[f32; 7]; 3] = [
-            [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-            [0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-            [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
-        ];
-        filter.set_measurement_matrix(Mat::from_slice_2d(&slice).unwrap());
+        KalmanFilter {
+            state: SVector::<f64, 7>::from_column_slice(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0]),
+            error_cov: SMatrix::identity(),
+            transition_matrix: SMatrix::identity(),
+            measurement_matrix,
+            process_noise_cov: SMatrix::zeros(),
+            measurement_noise_cov: SMatrix::identity() * 1e-5,
+            angular_jerk_noise: 10.0,
+            radius_noise: 0.1,
+            last_call: None
+        }
+    }
 
-        match Mat::eye(7, 7, CV_32F).unwrap() * 1e-4 {
-            MatExprResult::Ok(expr) => {
-                filter.set_process_noise_cov(expr.to_mat().unwrap());
-            }
-            MatExprResult::Err(_) => {}
+    // t is in seconds. Returns the initial gaze until the filter has seen a first timestamp.
+    pub fn predict(&mut self, t: f64) -> (f64, f64, f64) {
+        if self.last_call.is_none() {
+            self.last_call = Some(t);
+            return (-std::f64::consts::PI / 2.0, std::f64::consts::PI / 2.0, 0.0);
         }
 
-        match Mat::eye(3, 3, CV_32F).unwrap() * 1e-5 {
-            MatExprResult::Ok(expr) => {
-                filter.set_measurement_noise_cov(expr.to_mat().unwrap());
+        self.advance(t);
+
+        (self.state[0], self.state[1], self.state[6])
+    }
+
+    pub fn correct(&mut self, t: f64, phi: f64, theta: f64, radius: f64) {
+        self.advance(t);
+
+        let measurement = SVector::<f64, 3>::new(phi, theta, radius);
+        let residual_cov = self.measurement_matrix * self.error_cov * self.measurement_matrix.transpose() + self.measurement_noise_cov;
+        let residual_cov_inverse = match residual_cov.try_inverse() {
+            Some(inverse) => inverse,
+            None => return
+        };
+
+        let gain = self.error_cov * self.measurement_matrix.transpose() * residual_cov_inverse;
+        self.state += gain * (measurement - self.measurement_matrix * self.state);
+        self.error_cov -= gain * self.measurement_matrix * self.error_cov;
+    }
+
+    // Moves the state forward to t with the constant acceleration model, older timestamps leave it as is
+    fn advance(&mut self, t: f64) {
+        let dt = match self.last_call {
+            Some(last_call) if t > last_call => t - last_call,
+            Some(_) => return,
+            None => {
+                self.last_call = Some(t);
+                return;
             }
-            MatExprResult::Err(_) => {}
-        }
+        };
+
+        self.transition_matrix = SMatrix::<f64, 7, 7>::from_row_slice(&[
+            1.0, 0.0, dt, 0.0, 0.5 * dt * dt, 0.0, 0.0,
+            0.0, 1.0, 0.0, dt, 0.0, 0.5 * dt * dt, 0.0,
+            0.0, 0.0, 1.0, 0.0, dt, 0.0, 0.0,
+            0.0, 0.0, 0.0, 1.0, 0.0, dt, 0.0,
+            0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0
+        ]);
 
-        let state_slice: [f32; 7] = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0];
-        filter.set_state_post(Mat::from_slice(&state_slice).unwrap());
+        self.process_noise_cov = self.process_noise(dt);
+
+        self.state = self.transition_matrix * self.state;
+        self.error_cov = self.transition_matrix * self.error_cov * self.transition_matrix.transpose() + self.process_noise_cov;
+        self.last_call = Some(t);
+    }
+
+    // Continuous white noise jerk integrated over dt for each angle, a random walk for the radius
+    fn process_noise(&self, dt: f64) -> SMatrix<f64, 7, 7> {
+        let (dt2, dt3, dt4, dt5) = (dt.powi(2), dt.powi(3), dt.powi(4), dt.powi(5));
+        let block = [
+            [dt5 / 20.0, dt4 / 8.0, dt3 / 6.0],
+            [dt4 / 8.0, dt3 / 3.0, dt2 / 2.0],
+            [dt3 / 6.0, dt2 / 2.0, dt]
+        ];
 
-        match Mat::eye(7, 7, CV_32F) {
-            Ok(expr) => {
-                filter.set_error_cov_post(expr.to_mat().unwrap());
+        // The angles are interleaved as (phi, theta, phi', theta', phi'', theta'')
+        let mut process_noise = SMatrix::<f64, 7, 7>::zeros();
+        for angle in 0..2 {
+            for i in 0..3 {
+                for j in 0..3 {
+                    process_noise[(2 * i + angle, 2 * j + angle)] = self.angular_jerk_noise * block[i][j];
+                }
             }
-            Err(_) => {}
         }
+        process_noise[(6, 6)] = self.radius_noise * dt;
 
-        KalmanFilter {
-            filter,
-            last_call: -1
-        }
+        process_noise
     }
+}
 
-    pub fn predict(&mut self, t: i32) -> (f64, f64, f64) {
-        let (phi, theta, pupil_radius);
-
-        if self.last_call != -1 && t > self.last_call {
-            let dt: f64 = (t - self.last_call) as f64;
-            let slice: [[f64; 7]; 7] = [
-                [1.0, 0.0, dt, 0.0, 0.5 * dt * dt, 0.0, 0.0],
-                [0.0, 1.0, 0.0, dt, 0.0, 0.5 * dt * dt, 0.0],
-                [0.0, 0.0, 1.0, 0.0, dt, 0.0, 0.0],
-                [0.0, 0.0, 0.0, 1.0, 0.0, dt, 0.0],
-                [0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
-                [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
-                [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
-            ];
-            self.filter.set_measurement_matrix(Mat::from_slice_2d(&slice).unwrap());
-
-            let prediction = self.filter.predict_def().unwrap();
-            phi = *prediction.at_2d::<f64>(0, 0).unwrap();
-            theta = *prediction.at_2d::<f64>(1, 0).unwrap();
-            pupil_radius = *prediction.at_2d::<f64>(6, 0).unwrap();
+impl Default for KalmanFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_TIME: f64 = 1.0 / 200.0;
+
+    // Gaze sweeping at 0.5 rad/s with a fixed theta and pupil radius
+    fn track(t: f64) -> (f64, f64, f64) {
+        (-1.5 + 0.5 * t, 1.6, 2.0)
+    }
+
+    fn filter_on_track(n_frames: usize) -> KalmanFilter {
+        let mut filter = KalmanFilter::new();
+        for i in 0..n_frames {
+            let t = i as f64 * FRAME_TIME;
+            let (phi, theta, radius) = track(t);
+            filter.correct(t, phi, theta, radius);
         }
-        else {
-            (phi, theta, pupil_radius) = (-std::f64::consts::PI / 2.0, std::f64::consts::PI / 2.0, 0.0);
+        filter
+    }
+
+    #[test]
+    fn predict_before_any_data_returns_initial_gaze() {
+        let mut filter = KalmanFilter::new();
+        assert_eq!(filter.predict(3.0), (-std::f64::consts::PI / 2.0, std::f64::consts::PI / 2.0, 0.0));
+    }
+
+    #[test]
+    fn follows_constant_velocity_track() {
+        let mut filter = filter_on_track(200);
+        let t = 200.0 * FRAME_TIME;
+        let (phi, theta, radius) = filter.predict(t);
+        let expected = track(t);
+        assert!((phi - expected.0).abs() < 1e-3, "phi {} expected {}", phi, expected.0);
+        assert!((theta - expected.1).abs() < 1e-4);
+        assert!((radius - expected.2).abs() < 1e-4);
+        assert!((filter.state[2] - 0.5).abs() < 0.05, "phi velocity {}", filter.state[2]);
+    }
+
+    #[test]
+    fn extrapolates_over_a_gap() {
+        let mut filter = filter_on_track(200);
+        let last = 199.0 * FRAME_TIME;
+        for gap in [0.05, 0.1, 0.25] {
+            let (phi, _, radius) = filter.predict(last + gap);
+            let expected = track(last + gap);
+            assert!((phi - expected.0).abs() < 0.02 * gap.max(0.1), "gap {} phi {} expected {}", gap, phi, expected.0);
+            assert!((radius - expected.2).abs() < 1e-3);
         }
 
-        self.last_call = t;
+        // Uncertainty grows while there is no data, and the next measurement pulls the state back
+        let error_cov_after_gap = filter.error_cov[(0, 0)];
+        let t = last + 0.3;
+        let (phi, theta, radius) = track(t);
+        filter.correct(t, phi, theta, radius);
+        assert!(filter.error_cov[(0, 0)] < error_cov_after_gap);
+        assert!((filter.state[0] - phi).abs() < 1e-3);
+    }
 
-        return (phi, theta, pupil_radius)
+    #[test]
+    fn process_noise_scales_with_elapsed_time() {
+        let filter = KalmanFilter::new();
+        let short = filter.process_noise(0.005);
+        let long = filter.process_noise(0.5);
+        assert!(long[(0, 0)] / short[(0, 0)] > 1e9);
+        assert!((long[(6, 6)] / short[(6, 6)] - 100.0).abs() < 1e-9);
+        assert_eq!(long, long.transpose());
+        assert_eq!(long[(0, 1)], 0.0);
     }
 
-    pub fn correct(&mut self, phi: f64, theta: f64, radius: f64) {
-        let slice = [phi, theta, radius];
-        self.filter.correct(&Mat::from_slice(&slice).unwrap()).unwrap();
+    #[test]
+    fn older_timestamps_do_not_move_the_state() {
+        let mut filter = filter_on_track(50);
+        let state = filter.state;
+        filter.predict(0.0);
+        assert_eq!(filter.state, state);
     }
 }
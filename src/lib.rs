@@ -0,0 +1,19 @@
+pub mod background;
+#[cfg(feature = "opencv")]
+pub mod calibration;
+pub mod error;
+pub mod eye_parameters;
+pub mod kalman;
+pub mod refractionizer;
+pub mod simulator;
+pub mod two_sphere_model;
+pub mod observations;
+pub mod pipeline;
+pub mod primitive;
+pub mod projections;
+pub mod utils;
+// Module names follow pye3d
+#[allow(non_snake_case)]
+pub mod CameraModel;
+#[allow(non_snake_case)]
+pub mod Detector3D;
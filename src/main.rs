@@ -1,18 +1,73 @@
+#[cfg(feature = "opencv")]
+use std::path::Path;
 use ndarray::array;
+#[cfg(feature = "opencv")]
+use rs3d_detector::calibration;
+use rs3d_detector::error::DetectorError;
+use rs3d_detector::CameraModel::CameraModel;
+use rs3d_detector::Detector3D::Detector3D;
 
-mod kalman;
-mod refractionizer;
-mod two_sphere_model;
-mod observations;
-mod primitive;
-mod projections;
-mod utils;
-mod CameraModel;
-mod Detector3D;
-
-fn main() {
-    Detector3D::Detector3D::new(CameraModel::CameraModel {
-        focal_length: 25.0,
-        resolution: array![25.0, 25.0]
-    }, None, None);
-}
\ No newline at end of file
+#[cfg(feature = "opencv")]
+const CALIBRATE_USAGE: &str = "usage: calibrate <image dir> <output file> <columns> <rows> <square size> [--fisheye] [--charuco <marker size> <dictionary>]";
+
+fn main() -> Result<(), DetectorError> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("calibrate") {
+        return calibrate(&args[1..]);
+    }
+
+    Detector3D::new(CameraModel::new(25.0, array![25.0, 25.0]), None, None, None)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "opencv")]
+fn calibrate_usage() -> DetectorError {
+    DetectorError::Usage(CALIBRATE_USAGE.to_owned())
+}
+
+// Checkerboard sizes count inner corners, ChArUco sizes count squares
+#[cfg(feature = "opencv")]
+fn calibrate(args: &[String]) -> Result<(), DetectorError> {
+    if args.len() < 5 {
+        return Err(calibrate_usage());
+    }
+
+    let columns = args[2].parse().map_err(|_| calibrate_usage())?;
+    let rows = args[3].parse().map_err(|_| calibrate_usage())?;
+    let square_size = args[4].parse().map_err(|_| calibrate_usage())?;
+
+    let mut fisheye = false;
+    let mut pattern = calibration::CalibrationPattern::Checkerboard { columns, rows, square_size };
+    let mut options = args[5..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--fisheye" => fisheye = true,
+            "--charuco" => {
+                let marker_size = options.next().and_then(|value| value.parse().ok()).ok_or_else(calibrate_usage)?;
+                let dictionary = options.next().and_then(|value| calibration::parse_dictionary(value)).ok_or_else(calibrate_usage)?;
+                pattern = calibration::CalibrationPattern::Charuco { columns, rows, square_size, marker_size, dictionary };
+            }
+            _ => return Err(calibrate_usage())
+        }
+    }
+
+    let result = calibration::calibrate_directory(Path::new(&args[0]), &pattern, fisheye)?;
+    for image in &result.per_image {
+        println!("{}: {:.3} px", image.path.display(), image.rms_error);
+    }
+    for path in &result.skipped {
+        println!("{}: pattern not found", path.display());
+    }
+    println!("overall RMS reprojection error: {:.3} px over {} images", result.rms_error, result.per_image.len());
+
+    result.camera.save_intrinsics(Path::new(&args[1]))?;
+    println!("wrote intrinsics to {}", args[1]);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "opencv"))]
+fn calibrate(_args: &[String]) -> Result<(), DetectorError> {
+    Err(DetectorError::Usage("calibrate needs OpenCV, rebuild with --features opencv".to_owned()))
+}
@@ -1,6 +1,10 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
 use ndarray::{array, Array1, Array2, Array3, Axis, concatenate, s};
 use num_traits::ToPrimitive;
 use crate::CameraModel::CameraModel;
+use crate::error::DetectorError;
+use crate::eye_parameters::EyeParameters;
 use crate::primitive::{Ellipse, Line};
 use crate::projections::{Circle3D, project_line_onto_image_plane, unproject_ellipse};
 
@@ -10,6 +14,7 @@ pub struct Observation {
     pub confidence_2d: f64,
     pub confidence: f64,
     pub timestamp: f64,
+    pub pupil_distance: f64,
     pub invalid: bool,
     pub circle_3d_pair: Option<[Circle3D; 2]>,
     pub gaze_3d_pair: Option<Array1<Line>>,
@@ -19,17 +24,26 @@ pub struct Observation {
     pub aux_3d: Option<Array3<f64>>
 }
 
+// The part of an observation a sphere center fit reads
+#[derive(Clone)]
+pub struct FitLines {
+    pub gaze_2d: Line,
+    pub aux_2d: Array2<f64>,
+    pub aux_3d: Array3<f64>
+}
+
 pub struct BasicStorage {
     pub storage: Vec<Observation>
 }
 
 pub struct BufferedObservationStorage {
     pub confidence_threshold: f64,
-    pub storage: Vec<Observation>
+    pub buffer_length: usize,
+    pub storage: VecDeque<Observation>
 }
 
 pub struct BinBufferedObservationStorage {
-    pub camera: *const CameraModel,
+    pub camera: Arc<CameraModel>,
     pub confidence_threshold: f64,
     pub bin_buffer_length: usize,
     pub forget_min_observations: Option<usize>,
@@ -37,75 +51,133 @@ pub struct BinBufferedObservationStorage {
     pub pixels_per_bin: f64,
     pub w: usize,
     pub h: usize,
-    pub storage: Vec<Observation>
+    pub bins: Vec<VecDeque<Observation>>,
+    by_time: VecDeque<(f64, usize)>
 }
 
 impl Observation {
-    pub fn new(ellipse: Ellipse, confidence: f64, timestamp: f64, focal_length: f64, ) -> Observation {
-        let circle_3d_pair = unproject_ellipse(&ellipse, focal_length, 1.0);
-        let mut gaze_3d_pair = None;
-        let mut gaze_2d = None;
-        let mut gaze_2d_line = None;
-        if circle_3d_pair.is_some() {
-            let circle = circle_3d_pair.clone().unwrap()[0].clone();
-            gaze_3d_pair = Some(array!(Line::new(circle.center, circle.normal)));
-
-            gaze_2d = Some(project_line_onto_image_plane(gaze_3d_pair.clone().unwrap()[0].clone(), focal_length));
-            gaze_2d_line = Some(concatenate!(Axis(0), gaze_2d.clone().unwrap().origin, gaze_2d.clone().unwrap().direction));
-        }
+    pub fn new(ellipse: Ellipse, confidence: f64, timestamp: f64, focal_length: f64, eye_parameters: &EyeParameters) -> Observation {
+        let mut observation = Self::invalid(ellipse, confidence, timestamp, eye_parameters);
+
+        // Degenerate ellipses stay invalid and are ignored by every storage
+        let circle_3d_pair = match unproject_ellipse(&observation.ellipse, focal_length, 1.0) {
+            Some(circle_3d_pair) => circle_3d_pair,
+            None => return observation
+        };
+
+        let gaze_3d_pair = array!(
+            Line::new(circle_3d_pair[0].center.clone(), circle_3d_pair[0].normal.clone()),
+            Line::new(circle_3d_pair[1].center.clone(), circle_3d_pair[1].normal.clone())
+        );
+        observation.circle_3d_pair = Some(circle_3d_pair);
+
+        // Ellipses are already undistorted into the internal frame, where the pinhole projection is exact.
+        // Zero length lines can not be normalized, the observation stays invalid instead of carrying NaNs.
+        let gaze_2d = match project_line_onto_image_plane(gaze_3d_pair[0].clone(), focal_length) {
+            Some(gaze_2d) => gaze_2d,
+            None => return observation
+        };
+        let dierkes_lines = match (observation.get_dierkes_line(0), observation.get_dierkes_line(1)) {
+            (Some(first), Some(second)) => [first, second],
+            _ => return observation
+        };
+        let gaze_2d_line = concatenate!(Axis(0), gaze_2d.origin, gaze_2d.direction);
+
         let mut aux_2d = Array2::zeros((2, 3));
-        let v = gaze_2d.clone().unwrap().direction.clone().into_shape((2, 1)).unwrap();
+        let v = gaze_2d.direction.clone().into_shape((2, 1)).unwrap();
         let eye = Array2::eye(2);
         let vvt = &v * &v.t();
         let eye_minus_vvt = &eye - &vvt;
         aux_2d.slice_mut(s![.., ..2]).assign(&eye_minus_vvt);
-        aux_2d.slice_mut(s![.., 2]).assign(&(&eye_minus_vvt * gaze_2d.clone().unwrap().origin.clone()));
-
-        let mut observation = Observation {
-            ellipse,
-            confidence_2d: confidence,
-            confidence,
-            timestamp,
-            invalid: true,
-            circle_3d_pair,
-            gaze_3d_pair,
-            gaze_2d,
-            gaze_2d_line,
-            aux_2d: Option::from(aux_2d),
-            aux_3d: None
-        };
+        aux_2d.slice_mut(s![.., 2]).assign(&eye_minus_vvt.dot(&gaze_2d.origin));
 
         let mut aux_3d = Array3::zeros((2, 3, 4));
-        for i in 0..2 {
-            let dierkes_line = observation.get_dierkes_line(i);
+        for (i, dierkes_line) in dierkes_lines.iter().enumerate() {
             let v = dierkes_line.direction.to_shape((3, 1)).unwrap();
             let eye = Array2::eye(3);
             let vvt = &v * &v.t();
             let eye_minus_vvt = &eye - &vvt;
 
             aux_3d.slice_mut(s![i, .., ..3]).assign(&eye_minus_vvt);
-            aux_3d.slice_mut(s![i, .., 3]).assign(&(&eye_minus_vvt * dierkes_line.origin.clone()));
+            aux_3d.slice_mut(s![i, .., 3]).assign(&eye_minus_vvt.dot(&dierkes_line.origin));
+        }
+
+        observation.gaze_3d_pair = Some(gaze_3d_pair);
+        observation.gaze_2d = Some(gaze_2d);
+        observation.gaze_2d_line = Some(gaze_2d_line);
+        observation.aux_2d = Some(aux_2d);
+        observation.aux_3d = Some(aux_3d);
+        observation.invalid = false;
+        observation.confidence = observation.confidence_2d;
+
+        observation
+    }
 
+    // Ignored by every storage and never used for a prediction
+    pub fn invalid(ellipse: Ellipse, confidence: f64, timestamp: f64, eye_parameters: &EyeParameters) -> Observation {
+        Observation {
+            ellipse,
+            confidence_2d: confidence,
+            confidence: 0.0,
+            timestamp,
+            pupil_distance: eye_parameters.pupil_distance,
+            invalid: true,
+            circle_3d_pair: None,
+            gaze_3d_pair: None,
+            gaze_2d: None,
+            gaze_2d_line: None,
+            aux_2d: None,
+            aux_3d: None
         }
+    }
 
-        observation.aux_3d = Some(aux_3d.to_owned());
+    // The same observation in an internal frame scaled by scale. Unprojection is scale invariant, so only the
+    // 2D quantities change.
+    pub fn rescaled(&self, scale: f64) -> Observation {
+        let mut observation = self.clone();
+        observation.ellipse = Ellipse::new(
+            &self.ellipse.center * scale,
+            self.ellipse.minor_radius * scale,
+            self.ellipse.major_radius * scale,
+            self.ellipse.angle
+        );
+        if let Some(gaze_2d) = &self.gaze_2d {
+            observation.gaze_2d = Some(Line::new(&gaze_2d.origin * scale, gaze_2d.direction.clone()));
+        }
+        if let Some(gaze_2d_line) = observation.gaze_2d_line.as_mut() {
+            gaze_2d_line.slice_mut(s![..2]).mapv_inplace(|x| x * scale);
+        }
+        if let Some(aux_2d) = observation.aux_2d.as_mut() {
+            aux_2d.column_mut(2).mapv_inplace(|x| x * scale);
+        }
 
         observation
     }
 
-    pub fn get_dierkes_line(&self, i: usize) -> Line {
-        let circle_pair = self.circle_3d_pair.clone().unwrap()[i].clone();
-        let direction = circle_pair.center;
-        let origin = direction.clone() - 10.392304845413264 * circle_pair.normal;
-        Line::new(origin, direction)
+    pub fn fit_lines(&self) -> Option<FitLines> {
+        Some(FitLines {
+            gaze_2d: self.gaze_2d.clone()?,
+            aux_2d: self.aux_2d.clone()?,
+            aux_3d: self.aux_3d.clone()?
+        })
+    }
+
+    pub fn get_dierkes_line(&self, i: usize) -> Option<Line> {
+        let circle = self.circle_3d_pair.as_ref()?[i].clone();
+        let direction = circle.center;
+        let origin = direction.clone() - self.pupil_distance * circle.normal;
+        Line::try_new(origin, direction)
     }
 }
 
-pub trait ObservationStorage {
+// Storages live inside models that move between threads
+pub trait ObservationStorage: Send {
     fn add(&mut self, observation: Observation);
-    fn observations(&self) -> &Vec<Observation>;
+    fn observations(&self) -> Vec<&Observation>;
     fn clear(&mut self);
     fn count(&self) -> usize;
+    // Moves the stored observations into the internal frame of a new camera mode, scaled by scale
+    fn change_camera(&mut self, camera: &Arc<CameraModel>, scale: f64) -> Result<(), DetectorError>;
 }
 
 impl BasicStorage {
@@ -116,6 +188,12 @@ impl BasicStorage {
     }
 }
 
+impl Default for BasicStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ObservationStorage for BasicStorage {
     fn add(&mut self, observation: Observation) {
         if observation.invalid {
@@ -125,8 +203,8 @@ impl ObservationStorage for BasicStorage {
         self.storage.push(observation)
     }
 
-    fn observations(&self) -> &Vec<Observation> {
-        &self.storage
+    fn observations(&self) -> Vec<&Observation> {
+        self.storage.iter().collect()
     }
 
     fn clear(&mut self) {
@@ -136,28 +214,42 @@ impl ObservationStorage for BasicStorage {
     fn count(&self) -> usize {
         self.storage.len()
     }
+
+    fn change_camera(&mut self, _camera: &Arc<CameraModel>, scale: f64) -> Result<(), DetectorError> {
+        for observation in self.storage.iter_mut() {
+            *observation = observation.rescaled(scale);
+        }
+
+        Ok(())
+    }
 }
 
 impl BufferedObservationStorage {
     pub fn new(confidence_threshold: f64, buffer_length: usize) -> BufferedObservationStorage {
         BufferedObservationStorage {
             confidence_threshold,
-            storage: Vec::with_capacity(buffer_length)
+            buffer_length,
+            storage: VecDeque::with_capacity(buffer_length)
         }
     }
 }
 
 impl ObservationStorage for BufferedObservationStorage {
     fn add(&mut self, observation: Observation) {
-        if observation.invalid || observation.confidence < self.confidence_threshold {
+        if observation.invalid || observation.confidence < self.confidence_threshold || self.buffer_length == 0 {
             return;
         }
 
-        self.storage.push(observation)
+        // Drop the oldest observation once the buffer is full
+        if self.storage.len() >= self.buffer_length {
+            self.storage.pop_front();
+        }
+
+        self.storage.push_back(observation)
     }
 
-    fn observations(&self) -> &Vec<Observation> {
-        &self.storage
+    fn observations(&self) -> Vec<&Observation> {
+        self.storage.iter().collect()
     }
 
     fn clear(&mut self) {
@@ -167,38 +259,72 @@ impl ObservationStorage for BufferedObservationStorage {
     fn count(&self) -> usize {
         self.storage.len()
     }
+
+    fn change_camera(&mut self, _camera: &Arc<CameraModel>, scale: f64) -> Result<(), DetectorError> {
+        for observation in self.storage.iter_mut() {
+            *observation = observation.rescaled(scale);
+        }
+
+        Ok(())
+    }
 }
 
 impl BinBufferedObservationStorage {
     pub fn new(
-        camera: &CameraModel,
+        camera: Arc<CameraModel>,
         confidence_threshold: f64,
         n_bins_horizontal: usize,
         bin_buffer_length: usize,
         forget_min_observations: Option<usize>,
-        forget_min_time: Option<usize>) -> BinBufferedObservationStorage
+        forget_min_time: Option<usize>) -> Result<BinBufferedObservationStorage, DetectorError>
     {
         let camera_resolution = camera.resolution.to_owned();
         let pixels_per_bin = camera_resolution[0] / n_bins_horizontal as f64;
-        BinBufferedObservationStorage {
+        let w = n_bins_horizontal;
+        let h = (camera_resolution[1] / pixels_per_bin).round().to_usize()
+            .filter(|&h| w > 0 && h > 0)
+            .ok_or_else(|| DetectorError::InvalidCamera(format!("cannot bin resolution {}", camera_resolution)))?;
+        Ok(BinBufferedObservationStorage {
             camera,
             confidence_threshold,
             bin_buffer_length,
             forget_min_observations,
             forget_min_time,
             pixels_per_bin,
-            w: n_bins_horizontal,
-            h: (camera_resolution[1] / pixels_per_bin).round().to_usize().unwrap(),
-            storage: Vec::new()
-        }
+            w,
+            h,
+            bins: (0..w * h).map(|_| VecDeque::with_capacity(bin_buffer_length)).collect(),
+            by_time: VecDeque::new()
+        })
     }
 
-    fn get_bin(&self, observation: Observation) {
-        let mut ellipse_center = 0;
-        let mut resolution = 0;
+    fn get_bin(&self, observation: &Observation) -> usize {
+        // Ellipse centers are in the internal frame around the principal point
+        let image_point = self.camera.internal_to_image(&observation.ellipse.center);
+        let (x, y) = (image_point[0], image_point[1]);
 
-        //let meme = zip(observation.ellipse.center, &self.camera.resolution);
-        println!("aaa")
+        let x_bin = (x / self.pixels_per_bin).floor().clamp(0.0, (self.w - 1) as f64) as usize;
+        let y_bin = (y / self.pixels_per_bin).floor().clamp(0.0, (self.h - 1) as f64) as usize;
+
+        y_bin * self.w + x_bin
+    }
+
+    fn forget_old_observations(&mut self, current_time: f64) {
+        let (min_observations, min_time) = match (self.forget_min_observations, self.forget_min_time) {
+            (Some(min_observations), Some(min_time)) => (min_observations, min_time as f64),
+            _ => return
+        };
+
+        // Only forget once there is enough data left and the oldest observation is old enough
+        while self.by_time.len() > min_observations {
+            let (oldest_timestamp, bin) = *self.by_time.front().unwrap();
+            if current_time - oldest_timestamp <= min_time {
+                break;
+            }
+
+            self.by_time.pop_front();
+            self.bins[bin].pop_front();
+        }
     }
 }
 
@@ -208,21 +334,152 @@ impl ObservationStorage for BinBufferedObservationStorage {
             return
         }
 
+        let bin = self.get_bin(&observation);
+        let timestamp = observation.timestamp;
+
+        if self.bins[bin].len() >= self.bin_buffer_length {
+            self.bins[bin].pop_front();
+            if let Some(position) = self.by_time.iter().position(|&(_, b)| b == bin) {
+                self.by_time.remove(position);
+            }
+        }
+
+        self.bins[bin].push_back(observation);
+        self.by_time.push_back((timestamp, bin));
 
+        self.forget_old_observations(timestamp);
     }
 
-    fn observations(&self) -> &Vec<Observation> {
-        todo!()
+    fn observations(&self) -> Vec<&Observation> {
+        self.bins.iter().flatten().collect()
     }
 
     fn clear(&mut self) {
-        todo!()
+        for bin in self.bins.iter_mut() {
+            bin.clear();
+        }
+        self.by_time.clear();
     }
 
     fn count(&self) -> usize {
-        todo!()
+        self.by_time.len()
+    }
+
+    // Bins follow the new resolution, so rebin by replaying the observations in time order
+    fn change_camera(&mut self, camera: &Arc<CameraModel>, scale: f64) -> Result<(), DetectorError> {
+        let storage = BinBufferedObservationStorage::new(
+            camera.clone(),
+            self.confidence_threshold,
+            self.w,
+            self.bin_buffer_length,
+            self.forget_min_observations,
+            self.forget_min_time
+        )?;
+        let mut previous = std::mem::replace(self, storage);
+
+        for (_, bin) in previous.by_time.drain(..) {
+            if let Some(observation) = previous.bins[bin].pop_front() {
+                self.add(observation.rescaled(scale));
+            }
+        }
+
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(center: Array1<f64>, timestamp: f64) -> Observation {
+        Observation::new(Ellipse::new(center, 8.0, 10.0, 0.3), 0.99, timestamp, 620.0, &EyeParameters::default())
+    }
+
+    // 10x10 bins of 40 pixels
+    fn bin_buffered_storage(forget_min_observations: Option<usize>, forget_min_time: Option<usize>) -> BinBufferedObservationStorage {
+        let camera = Arc::new(CameraModel::new(620.0, array![400.0, 400.0]));
+        BinBufferedObservationStorage::new(camera, 0.8, 10, 3, forget_min_observations, forget_min_time).unwrap()
+    }
+
+    // Centers on the diagonal, one per bin
+    fn spread_center(i: usize) -> Array1<f64> {
+        array![-180.0 + 40.0 * i as f64, -180.0 + 40.0 * i as f64]
+    }
+
+    fn timestamps(observations: Vec<&Observation>) -> Vec<f64> {
+        observations.iter().map(|observation| observation.timestamp).collect()
+    }
+
+    fn assert_in_sync(storage: &BinBufferedObservationStorage) {
+        assert_eq!(storage.count(), storage.observations().len());
+        for (bin, observations) in storage.bins.iter().enumerate() {
+            let by_time: Vec<f64> = storage.by_time.iter().filter(|&&(_, b)| b == bin).map(|&(timestamp, _)| timestamp).collect();
+            assert_eq!(timestamps(observations.iter().collect()), by_time);
+            assert!(observations.iter().all(|observation| storage.get_bin(observation) == bin));
+        }
+        assert!(storage.by_time.iter().zip(storage.by_time.iter().skip(1)).all(|(a, b)| a.0 <= b.0));
+    }
+
+    #[test]
+    fn valid_observation_has_finite_fit_lines() {
+        let ellipse = Ellipse::new(array![40.0, -25.0], 8.0, 10.0, 0.3);
+        let observation = Observation::new(ellipse, 0.99, 0.0, 620.0, &EyeParameters::default());
+        let lines = observation.fit_lines().unwrap();
+        assert!(!observation.invalid);
+        assert!(lines.aux_2d.iter().chain(lines.aux_3d.iter()).all(|x| x.is_finite()));
+    }
 
+    #[test]
+    fn full_bin_keeps_its_newest_observations() {
+        let mut storage = bin_buffered_storage(None, None);
+        for i in 0..10 {
+            storage.add(observation(array![12.0, -7.0], i as f64));
+            // Another bin between the additions must not lose anything
+            storage.add(observation(spread_center(0), i as f64 + 0.5));
+            assert_in_sync(&storage);
+        }
 
+        let bin = storage.get_bin(&observation(array![12.0, -7.0], 0.0));
+        assert_eq!(timestamps(storage.bins[bin].iter().collect()), vec![7.0, 8.0, 9.0]);
+        assert_eq!(storage.count(), 6);
+    }
+
+    #[test]
+    fn spread_observations_are_forgotten_past_both_thresholds() {
+        let mut storage = bin_buffered_storage(Some(5), Some(10));
+
+        // More than forget_min_observations, but none older than forget_min_time
+        for i in 0..8 {
+            storage.add(observation(spread_center(i), i as f64));
+        }
+        assert_eq!(storage.count(), 8);
+        assert_in_sync(&storage);
+
+        // Everything before t = 10 is old now, but only down to forget_min_observations
+        storage.add(observation(spread_center(8), 20.0));
+        assert_eq!(storage.observations().len(), 5);
+        assert_eq!(storage.by_time.iter().map(|&(timestamp, _)| timestamp).collect::<Vec<f64>>(), vec![4.0, 5.0, 6.0, 7.0, 20.0]);
+        assert_in_sync(&storage);
+
+        // Without both thresholds nothing is forgotten
+        for (min_observations, min_time) in [(Some(5), None), (None, Some(10))] {
+            let mut storage = bin_buffered_storage(min_observations, min_time);
+            for i in 0..9 {
+                storage.add(observation(spread_center(i), 10.0 * i as f64));
+            }
+            assert_eq!(storage.count(), 9);
+            assert_in_sync(&storage);
+        }
+    }
+
+    #[test]
+    fn ring_buffer_keeps_the_last_observations_in_order() {
+        let mut storage = BufferedObservationStorage::new(0.8, 5);
+        for i in 0..8 {
+            storage.add(observation(array![12.0, -7.0], i as f64));
+        }
+
+        assert_eq!(storage.count(), 5);
+        assert_eq!(timestamps(storage.observations()), vec![3.0, 4.0, 5.0, 6.0, 7.0]);
+    }
+}
@@ -0,0 +1,469 @@
+use std::fmt;
+use nalgebra::DMatrix;
+use ndarray::{Array1, Array2, ArrayView2, Axis};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use serde_derive::{Deserialize, Serialize};
+use crate::error::DetectorError;
+
+pub trait Step: Send + Sync {
+    // Number of input columns the step was fitted on, None if it takes any number
+    fn n_features_in(&self) -> Option<usize>;
+    fn n_features_out(&self, n_features_in: usize) -> usize;
+    fn transform(&self, x: ArrayView2<f64>) -> Array2<f64>;
+}
+
+pub struct Pipeline {
+    pub steps: Vec<Box<dyn Step>>
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct PolynomialParams {
+    pub degree: u8,
+    pub include_bias: bool,
+    pub interaction_only: bool,
+    pub order: String
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct PolynomialFeatures {
+    pub params: PolynomialParams,
+    // (n_inputs, n_features), generated from params when missing
+    #[serde(default, with = "optional_ndim")]
+    pub powers: Option<Array2<f64>>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct StandardScalerParams {
+    pub copy: bool,
+    pub with_mean: bool,
+    pub with_std: bool
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct StandardScaler {
+    pub params: StandardScalerParams,
+    #[serde(default, with = "optional_ndim")]
+    pub mean: Option<Array2<f64>>,
+    #[serde(default, with = "optional_ndim")]
+    pub var: Option<Array2<f64>>
+}
+
+// Field names are sklearn's parameter names
+#[allow(non_snake_case)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct LinearRegressionParams {
+    pub copy_X: bool,
+    pub fit_intercept: bool,
+    pub n_jobs: Option<u8>,
+    pub normalize: bool,
+    pub positive: bool
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct LinearRegression {
+    pub params: LinearRegressionParams,
+    // (n_features, n_outputs)
+    #[serde(with = "serde_ndim")]
+    pub coef: Array2<f64>,
+    #[serde(default, with = "optional_ndim")]
+    pub intercept: Option<Array2<f64>>,
+}
+
+// The PolynomialFeatures -> StandardScaler -> LinearRegression layout of the refraction models
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PolynomialRegression {
+    pub polynomial_features: PolynomialFeatures,
+    pub standard_scaler: StandardScaler,
+    pub linear_regression: LinearRegression
+}
+
+impl Pipeline {
+    pub fn new(steps: Vec<Box<dyn Step>>) -> Pipeline {
+        Pipeline {
+            steps
+        }
+    }
+
+    pub fn push(&mut self, step: Box<dyn Step>) {
+        self.steps.push(step)
+    }
+
+    pub fn n_features_in(&self) -> Option<usize> {
+        self.steps.first()?.n_features_in()
+    }
+
+    // Follows n_inputs columns through every step and returns the number of output columns
+    pub fn check_input(&self, n_inputs: usize) -> Result<usize, DetectorError> {
+        let mut n_features = n_inputs;
+        for (i, step) in self.steps.iter().enumerate() {
+            match step.n_features_in() {
+                Some(expected) if expected != n_features => {
+                    return Err(DetectorError::InvalidInput(format!("step {} expects {} columns, got {}", i, expected, n_features)));
+                }
+                _ => n_features = step.n_features_out(n_features)
+            }
+        }
+
+        Ok(n_features)
+    }
+
+    // x is (n_samples, n_inputs)
+    pub fn predict(&self, x: ArrayView2<f64>) -> Result<Array2<f64>, DetectorError> {
+        self.check_input(x.ncols())?;
+
+        let mut y = x.to_owned();
+        for step in &self.steps {
+            y = step.transform(y.view());
+        }
+
+        Ok(y)
+    }
+}
+
+impl PolynomialFeatures {
+    // Same feature order as sklearn: bias, then every combination of inputs per degree in lexicographic order
+    pub fn generate_powers(n_inputs: usize, degree: u8, include_bias: bool, interaction_only: bool) -> Array2<f64> {
+        let mut combinations = Vec::new();
+        let first_degree = if include_bias { 0 } else { 1 };
+        for k in first_degree..=degree as usize {
+            Self::push_combinations(n_inputs, k, !interaction_only, 0, &mut Vec::new(), &mut combinations);
+        }
+
+        let mut powers = Array2::zeros((n_inputs, combinations.len()));
+        for (i, combination) in combinations.iter().enumerate() {
+            for &j in combination {
+                powers[[j, i]] += 1.0;
+            }
+        }
+
+        powers
+    }
+
+    fn push_combinations(n_inputs: usize, k: usize, with_replacement: bool, start: usize, prefix: &mut Vec<usize>, combinations: &mut Vec<Vec<usize>>) {
+        if prefix.len() == k {
+            combinations.push(prefix.clone());
+            return;
+        }
+
+        for j in start..n_inputs {
+            prefix.push(j);
+            let next = if with_replacement { j } else { j + 1 };
+            Self::push_combinations(n_inputs, k, with_replacement, next, prefix, combinations);
+            prefix.pop();
+        }
+    }
+}
+
+impl PolynomialFeatures {
+    pub fn fit(n_inputs: usize, degree: u8, include_bias: bool, interaction_only: bool) -> PolynomialFeatures {
+        PolynomialFeatures {
+            params: PolynomialParams {
+                degree,
+                include_bias,
+                interaction_only,
+                order: "F".to_owned()
+            },
+            powers: Some(Self::generate_powers(n_inputs, degree, include_bias, interaction_only))
+        }
+    }
+}
+
+impl StandardScaler {
+    pub fn fit(x: ArrayView2<f64>, with_mean: bool, with_std: bool) -> StandardScaler {
+        let n_features = x.ncols();
+        let mean = x.mean_axis(Axis(0)).unwrap_or_else(|| Array1::zeros(n_features));
+        let var = if x.nrows() > 0 { x.var_axis(Axis(0), 0.0) } else { Array1::ones(n_features) };
+
+        StandardScaler {
+            params: StandardScalerParams {
+                copy: true,
+                with_mean,
+                with_std
+            },
+            mean: (with_mean || with_std).then(|| mean.into_shape((n_features, 1)).unwrap()),
+            var: with_std.then(|| var.into_shape((n_features, 1)).unwrap())
+        }
+    }
+}
+
+impl LinearRegression {
+    // Ordinary least squares, solved through the SVD so rank deficient features are fine
+    pub fn fit(x: ArrayView2<f64>, y: ArrayView2<f64>, fit_intercept: bool) -> Result<LinearRegression, DetectorError> {
+        if x.nrows() != y.nrows() || x.nrows() == 0 {
+            return Err(DetectorError::InvalidTrainingData(format!("{} inputs for {} targets", x.nrows(), y.nrows())));
+        }
+
+        let (x_offset, y_offset) = if fit_intercept {
+            (x.mean_axis(Axis(0)).unwrap(), y.mean_axis(Axis(0)).unwrap())
+        } else {
+            (Array1::zeros(x.ncols()), Array1::zeros(y.ncols()))
+        };
+        let x_centered = &x - &x_offset;
+        let y_centered = &y - &y_offset;
+
+        let a = DMatrix::from_fn(x.nrows(), x.ncols(), |i, j| x_centered[[i, j]]);
+        let b = DMatrix::from_fn(y.nrows(), y.ncols(), |i, j| y_centered[[i, j]]);
+        let solution = a.svd(true, true).solve(&b, 1e-12).map_err(|_| DetectorError::NoSolution)?;
+
+        let coef = Array2::from_shape_fn((x.ncols(), y.ncols()), |(i, j)| solution[(i, j)]);
+        let intercept = (&y_offset - &x_offset.dot(&coef)).insert_axis(Axis(0));
+
+        Ok(LinearRegression {
+            params: LinearRegressionParams {
+                copy_X: true,
+                fit_intercept,
+                n_jobs: None,
+                normalize: false,
+                positive: false
+            },
+            coef,
+            intercept: fit_intercept.then_some(intercept)
+        })
+    }
+}
+
+impl PolynomialRegression {
+    // x is (n_samples, n_inputs) of uncorrected values, y is (n_samples, n_outputs) of their corrections
+    pub fn fit(x: ArrayView2<f64>, y: ArrayView2<f64>, degree: u8) -> Result<PolynomialRegression, DetectorError> {
+        let polynomial_features = PolynomialFeatures::fit(x.ncols(), degree, false, false);
+        let features = polynomial_features.transform(x);
+        let standard_scaler = StandardScaler::fit(features.view(), true, true);
+        let scaled_features = standard_scaler.transform(features.view());
+        let linear_regression = LinearRegression::fit(scaled_features.view(), y, true)?;
+
+        Ok(PolynomialRegression {
+            polynomial_features,
+            standard_scaler,
+            linear_regression
+        })
+    }
+
+    pub fn into_pipeline(self) -> Pipeline {
+        Pipeline::new(vec![
+            Box::new(self.polynomial_features),
+            Box::new(self.standard_scaler),
+            Box::new(self.linear_regression)
+        ])
+    }
+}
+
+impl Step for PolynomialFeatures {
+    fn n_features_in(&self) -> Option<usize> {
+        self.powers.as_ref().map(|powers| powers.nrows())
+    }
+
+    fn n_features_out(&self, n_features_in: usize) -> usize {
+        match &self.powers {
+            Some(powers) => powers.ncols(),
+            None => Self::generate_powers(n_features_in, self.params.degree, self.params.include_bias, self.params.interaction_only).ncols()
+        }
+    }
+
+    fn transform(&self, x: ArrayView2<f64>) -> Array2<f64> {
+        let generated;
+        let powers = match &self.powers {
+            Some(powers) => powers,
+            None => {
+                generated = Self::generate_powers(x.ncols(), self.params.degree, self.params.include_bias, self.params.interaction_only);
+                &generated
+            }
+        };
+
+        let mut features = Array2::<f64>::ones((x.nrows(), powers.ncols()));
+        for (i, mut feature) in features.axis_iter_mut(Axis(1)).enumerate() {
+            for (j, input) in x.axis_iter(Axis(1)).enumerate() {
+                let power = powers[[j, i]] as i32;
+                if power != 0 {
+                    feature.zip_mut_with(&input, |f, &x| *f *= x.powi(power));
+                }
+            }
+        }
+
+        features
+    }
+}
+
+impl Step for StandardScaler {
+    fn n_features_in(&self) -> Option<usize> {
+        self.mean.as_ref().or(self.var.as_ref()).map(|a| a.len())
+    }
+
+    fn n_features_out(&self, n_features_in: usize) -> usize {
+        n_features_in
+    }
+
+    fn transform(&self, x: ArrayView2<f64>) -> Array2<f64> {
+        let mut y = x.to_owned();
+        if let (true, Some(mean)) = (self.params.with_mean, &self.mean) {
+            y -= &flatten(mean);
+        }
+        if let (true, Some(var)) = (self.params.with_std, &self.var) {
+            // Constant features keep their scale like in sklearn
+            y /= &flatten(var).mapv(|v| if v == 0.0 { 1.0 } else { v.sqrt() });
+        }
+
+        y
+    }
+}
+
+impl Step for LinearRegression {
+    fn n_features_in(&self) -> Option<usize> {
+        Some(self.coef.nrows())
+    }
+
+    fn n_features_out(&self, _n_features_in: usize) -> usize {
+        self.coef.ncols()
+    }
+
+    fn transform(&self, x: ArrayView2<f64>) -> Array2<f64> {
+        let y = x.dot(&self.coef);
+        match (self.params.fit_intercept, &self.intercept) {
+            (true, Some(intercept)) => y + &flatten(intercept),
+            _ => y
+        }
+    }
+}
+
+fn flatten(a: &Array2<f64>) -> Array1<f64> {
+    a.iter().copied().collect()
+}
+
+// Steps are stored as a map from sklearn class name to step, in pipeline order
+impl<'de> Deserialize<'de> for Pipeline {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PipelineVisitor;
+
+        impl<'de> Visitor<'de> for PipelineVisitor {
+            type Value = Pipeline;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of pipeline steps")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Pipeline, A::Error> {
+                let mut steps: Vec<Box<dyn Step>> = Vec::new();
+                while let Some(name) = map.next_key::<String>()? {
+                    match name.as_str() {
+                        "PolynomialFeatures" => steps.push(Box::new(map.next_value::<PolynomialFeatures>()?)),
+                        "StandardScaler" => steps.push(Box::new(map.next_value::<StandardScaler>()?)),
+                        "LinearRegression" => steps.push(Box::new(map.next_value::<LinearRegression>()?)),
+                        _ => return Err(de::Error::unknown_variant(&name, STEP_NAMES))
+                    }
+                }
+
+                Ok(Pipeline::new(steps))
+            }
+        }
+
+        deserializer.deserialize_map(PipelineVisitor)
+    }
+}
+
+const STEP_NAMES: &[&str] = &["PolynomialFeatures", "StandardScaler", "LinearRegression"];
+
+mod optional_ndim {
+    use ndarray::Array2;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(with = "serde_ndim")] Array2<f64>);
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Array2<f64>>, D::Error> {
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|wrapper| wrapper.0))
+    }
+
+    pub fn serialize<S: Serializer>(array: &Option<Array2<f64>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match array {
+            Some(array) => serde_ndim::serialize(array, serializer),
+            None => serializer.serialize_none()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{array, s};
+
+    fn quadratic_pipeline() -> Pipeline {
+        let x = Array2::from_shape_fn((40, 2), |(i, j)| if j == 0 { (i as f64 * 0.37).sin() } else { (i as f64 * 1.91).cos() });
+        let y = x.map_axis(Axis(1), |row| 1.0 + 2.0 * row[0] - row[1] * row[1]).insert_axis(Axis(1));
+        PolynomialRegression::fit(x.view(), y.view(), 2).unwrap().into_pipeline()
+    }
+
+    #[test]
+    fn predict_rejects_wrong_column_count() {
+        let pipeline = quadratic_pipeline();
+        assert_eq!(pipeline.check_input(2).unwrap(), 1);
+        assert!(matches!(pipeline.predict(Array2::zeros((3, 3)).view()), Err(DetectorError::InvalidInput(_))));
+
+        let y = pipeline.predict(array![[0.5, -0.25]].view()).unwrap();
+        assert!((y[[0, 0]] - (1.0 + 1.0 - 0.0625)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn check_input_catches_mismatched_steps() {
+        let mut pipeline = quadratic_pipeline();
+        pipeline.steps.remove(0);
+        assert!(matches!(pipeline.check_input(2), Err(DetectorError::InvalidInput(_))));
+        assert_eq!(pipeline.check_input(5).unwrap(), 1);
+    }
+
+    #[test]
+    fn polynomial_features_follow_sklearn_order() {
+        // PolynomialFeatures(degree=2).fit(np.zeros((1, 3))).powers_
+        let powers = array![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0],
+            [2.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 0.0, 1.0], [0.0, 2.0, 0.0], [0.0, 1.0, 1.0], [0.0, 0.0, 2.0]
+        ];
+        // The same with interaction_only=True
+        let interaction_powers = array![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0],
+            [1.0, 1.0, 0.0], [1.0, 0.0, 1.0], [0.0, 1.0, 1.0]
+        ];
+
+        // powers_ is (n_output_features, n_inputs), PolynomialFeatures stores its transpose
+        assert_eq!(PolynomialFeatures::generate_powers(3, 2, true, false), powers.t());
+        assert_eq!(PolynomialFeatures::generate_powers(3, 2, false, false), powers.slice(s![1.., ..]).t());
+        assert_eq!(PolynomialFeatures::generate_powers(3, 2, true, true), interaction_powers.t());
+        assert_eq!(PolynomialFeatures::generate_powers(3, 2, false, true), interaction_powers.slice(s![1.., ..]).t());
+    }
+
+    #[test]
+    fn standard_scaler_flags_match_sklearn() {
+        // Variance 8/3 in the first column, the constant second column keeps its scale
+        let x = array![[1.0, 10.0], [3.0, 10.0], [5.0, 10.0]];
+        let std = (8.0f64 / 3.0).sqrt();
+
+        let scaler = StandardScaler::fit(x.view(), true, false);
+        assert!(scaler.var.is_none());
+        assert_eq!(scaler.transform(x.view()), array![[-2.0, 0.0], [0.0, 0.0], [2.0, 0.0]]);
+
+        let scaler = StandardScaler::fit(x.view(), false, true);
+        let expected = array![[1.0 / std, 10.0], [3.0 / std, 10.0], [5.0 / std, 10.0]];
+        assert!((scaler.transform(x.view()) - expected).iter().all(|d| d.abs() < 1e-12));
+
+        let scaler = StandardScaler::fit(x.view(), false, false);
+        assert!(scaler.mean.is_none() && scaler.var.is_none());
+        assert_eq!(scaler.transform(x.view()), x);
+    }
+
+    #[test]
+    fn linear_regression_without_intercept_fits_through_the_origin() {
+        let x = array![[1.0, 0.0], [0.0, 1.0], [1.0, 1.0], [2.0, 1.0]];
+        let y = x.map_axis(Axis(1), |row| 2.0 * row[0] - row[1] + 3.0).insert_axis(Axis(1));
+
+        let with_intercept = LinearRegression::fit(x.view(), y.view(), true).unwrap();
+        assert!((with_intercept.transform(array![[1.0, 2.0]].view())[[0, 0]] - 3.0).abs() < 1e-9);
+
+        // Least squares through the origin gives coef_ [3, 1] and intercept_ 0
+        let without_intercept = LinearRegression::fit(x.view(), y.view(), false).unwrap();
+        assert!(without_intercept.intercept.is_none());
+        assert!((&without_intercept.coef - &array![[3.0], [1.0]]).iter().all(|d| d.abs() < 1e-9));
+        assert!((without_intercept.transform(array![[1.0, 2.0]].view())[[0, 0]] - 5.0).abs() < 1e-9);
+    }
+}
@@ -1,4 +1,5 @@
-use ndarray::{Array1, ArrayD};
+use nalgebra::{Matrix2, Matrix3, Vector3};
+use ndarray::{array, Array1, ArrayView2};
 use std::f64::consts::{PI};
 
 #[derive(Clone)]
@@ -16,9 +17,9 @@ pub struct Line {
     pub shape: Vec<usize>
 }
 
-pub struct Circle {
+#[derive(Clone)]
+pub struct Sphere {
     pub center: Array1<f64>,
-    pub normal: Array1<f64>,
     pub radius: f64
 }
 
@@ -76,31 +77,149 @@ impl Ellipse {
     pub fn circularity(&self) -> f64 {
         self.minor_radius / self.major_radius
     }
+
+    // Maps every point (x, y) of the ellipse to (sx * x + tx, sy * y + ty)
+    pub fn scale_and_translate(&self, sx: f64, sy: f64, tx: f64, ty: f64) -> Ellipse {
+        let (cos, sin) = (self.angle.cos(), self.angle.sin());
+        let axes = Matrix2::new(
+            sx * cos * self.major_radius, -sx * sin * self.minor_radius,
+            sy * sin * self.major_radius, sy * cos * self.minor_radius
+        );
+
+        // The singular vectors of the scaled axes are the new principal axes
+        let svd = axes.svd(true, false);
+        let major_axis = svd.u.unwrap().column(0).into_owned();
+        Ellipse::new(
+            array!(sx * self.center[0] + tx, sy * self.center[1] + ty),
+            svd.singular_values[1],
+            svd.singular_values[0],
+            major_axis[1].atan2(major_axis[0])
+        )
+    }
+
+    // Direct least squares fit of an ellipse to (n, 2) points, Halir and Flusser 1998
+    pub fn fit(points: ArrayView2<f64>) -> Option<Ellipse> {
+        if points.nrows() < 5 {
+            return None
+        }
+
+        // Center and scale the points to keep the scatter matrices well conditioned
+        let offset = points.mean_axis(ndarray::Axis(0))?;
+        let scale = points.rows().into_iter()
+            .map(|p| (p[0] - offset[0]).abs().max((p[1] - offset[1]).abs()))
+            .fold(0.0, f64::max);
+        if scale == 0.0 {
+            return None
+        }
+
+        let mut s1 = Matrix3::zeros();
+        let mut s2 = Matrix3::zeros();
+        let mut s3 = Matrix3::zeros();
+        for p in points.rows() {
+            let (x, y) = ((p[0] - offset[0]) / scale, (p[1] - offset[1]) / scale);
+            let d1 = Vector3::new(x * x, x * y, y * y);
+            let d2 = Vector3::new(x, y, 1.0);
+            s1 += d1 * d1.transpose();
+            s2 += d1 * d2.transpose();
+            s3 += d2 * d2.transpose();
+        }
+
+        let t = -s3.try_inverse()? * s2.transpose();
+        let m = s1 + s2 * t;
+        // Premultiply by the inverse of the constraint 4ac - b^2 = 1
+        let m = Matrix3::from_rows(&[
+            m.row(2) / 2.0,
+            -m.row(1),
+            m.row(0) / 2.0
+        ]);
+
+        let quadratic = m.complex_eigenvalues().iter()
+            .filter(|lambda| lambda.im.abs() <= 1e-9 * lambda.re.abs().max(1.0))
+            .map(|lambda| {
+                let reduced = m - Matrix3::identity() * lambda.re;
+                let (r0, r1, r2) = (reduced.row(0).transpose(), reduced.row(1).transpose(), reduced.row(2).transpose());
+                let candidates = [r0.cross(&r1), r0.cross(&r2), r1.cross(&r2)];
+                *candidates.iter().max_by(|x, y| x.norm_squared().total_cmp(&y.norm_squared())).unwrap()
+            })
+            .find(|v| 4.0 * v[0] * v[2] - v[1] * v[1] > 0.0)?;
+        let linear = t * quadratic;
+
+        Self::from_conic_coefficients(
+            quadratic[0],
+            quadratic[1],
+            quadratic[2],
+            linear[0],
+            linear[1],
+            linear[2]
+        ).map(|ellipse| Ellipse::new(
+            array!(ellipse.center[0] * scale + offset[0], ellipse.center[1] * scale + offset[1]),
+            ellipse.minor_radius * scale,
+            ellipse.major_radius * scale,
+            ellipse.angle
+        ))
+    }
+
+    // a x^2 + b xy + c y^2 + d x + e y + f = 0
+    fn from_conic_coefficients(a: f64, b: f64, c: f64, d: f64, e: f64, f: f64) -> Option<Ellipse> {
+        let det = 4.0 * a * c - b * b;
+        if det <= 0.0 {
+            return None
+        }
+
+        let x0 = (b * e - 2.0 * c * d) / det;
+        let y0 = (b * d - 2.0 * a * e) / det;
+        let f0 = f + (d * x0 + e * y0) / 2.0;
+
+        // Flip the sign so the quadratic form is positive definite
+        let (a, b, c, f0) = if f0 > 0.0 { (-a, -b, -c, -f0) } else { (a, b, c, f0) };
+        if f0 == 0.0 || a + c <= 0.0 {
+            return None
+        }
+
+        let root = ((a - c).powi(2) + b * b).sqrt();
+        let lambda_large = (a + c + root) / 2.0;
+        let lambda_small = (a + c - root) / 2.0;
+        let angle = 0.5 * b.atan2(a - c);
+
+        // The major axis is orthogonal to the eigenvector of the larger eigenvalue
+        Some(Ellipse::new(
+            array!(x0, y0),
+            (-f0 / lambda_large).sqrt(),
+            (-f0 / lambda_small).sqrt(),
+            angle + PI / 2.0
+        ))
+    }
 }
 
 impl Line {
     pub fn new(origin: Array1<f64>, direction: Array1<f64>) -> Line {
         let shape = origin.shape().to_owned();
+        let direction = &direction / direction.dot(&direction).sqrt();
         Line {
             origin,
             direction,
             shape
         }
     }
+
+    // None if the direction has no length or is not finite, it can not be normalized then
+    pub fn try_new(origin: Array1<f64>, direction: Array1<f64>) -> Option<Line> {
+        let length = direction.dot(&direction).sqrt();
+        if !(length > 0.0 && length.is_finite()) || origin.iter().any(|x| !x.is_finite()) {
+            return None;
+        }
+
+        Some(Line::new(origin, direction))
+    }
 }
 
-impl Circle {
-    pub fn new(center: Array1<f64>, normal: Array1<f64>, radius: f64) -> Circle {
-        Circle {
+impl Sphere {
+    pub fn new(center: Array1<f64>, radius: f64) -> Sphere {
+        Sphere {
             center,
-            normal,
             radius
         }
     }
-
-    pub fn spherical_representation(&self) -> (f64, f64, f64) {
-        todo!()
-    }
 }
 
 impl Conic {
@@ -110,7 +229,7 @@ impl Conic {
         let a2 = ellipse.major_radius.powi(2);
         let b2 = ellipse.minor_radius.powi(2);
 
-        let a = a2 * ay * ay * b2 * ax * ax;
+        let a = a2 * ay * ay + b2 * ax * ax;
         let b = 2.0 * (b2 - a2) * ax * ay;
         let c = a2 * ax * ax + b2 * ay * ay;
         Conic {
@@ -146,7 +265,7 @@ impl Conicoid {
             + conic.e * beta
             + conic.f;
         let f = -gamma * (conic.c * beta + conic.b / 2.0 * alpha + conic.e / 2.0);
-        let g = -gamma * (conic.c / 2.0 * beta + conic.a * alpha + conic.d / 2.0);
+        let g = -gamma * (conic.b / 2.0 * beta + conic.a * alpha + conic.d / 2.0);
         let h = gamma.powi(2) * conic.b / 2.0;
         let u = gamma.powi(2) * conic.d / 2.0;
         let v = gamma.powi(2) * conic.e / 2.0;
@@ -1,7 +1,10 @@
-use nalgebra::{DMatrix, DVector, Matrix1x2, Matrix1x3, Matrix2, SVector, Vector1, Vector2, Vector3};
+use std::f64::consts::PI;
+use nalgebra::{Matrix3, SVector, Vector2, Vector3};
 use ndarray::{array, Array1, s};
-use crate::primitive::{Conic, Conicoid, Line};
+use crate::error::DetectorError;
+use crate::primitive::{Conic, Conicoid, Line, Sphere};
 use crate::primitive::Ellipse;
+use crate::utils::cart2sph;
 
 #[derive(Clone)]
 pub struct Circle3D {
@@ -10,21 +13,40 @@ pub struct Circle3D {
     pub radius: f64
 }
 
-pub fn solve_1(a: f64) -> Result<f64, &'static str> {
+impl Circle3D {
+    pub fn null() -> Circle3D {
+        Circle3D {
+            center: Array1::zeros(3),
+            normal: Array1::zeros(3),
+            radius: 0.0
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.radius <= 0.0
+    }
+
+    pub fn spherical_representation(&self) -> (f64, f64, f64) {
+        let (phi, theta) = cart2sph(self.normal.clone());
+        (phi, theta, self.radius)
+    }
+}
+
+pub fn solve_1(a: f64) -> Result<f64, DetectorError> {
     if a == 0.0 {
         return Ok(0.0)
     }
-    Err("No Solution")
+    Err(DetectorError::NoSolution)
 }
 
-pub fn solve_2(a: f64, b: f64) -> Result<f64, &'static str> {
+pub fn solve_2(a: f64, b: f64) -> Result<f64, DetectorError> {
     if a == 0.0 {
         return solve_1(b)
     }
     Ok(-b / a)
 }
 
-pub fn solve_3(a: f64, b: f64, c: f64) -> Result<SVector<f64, 2>, &'static str> {
+pub fn solve_3(a: f64, b: f64, c: f64) -> Result<SVector<f64, 2>, DetectorError> {
     if a == 0.0 {
         return match solve_2(b, c) {
             Ok(root) => Ok(Vector2::new(root, root)),
@@ -37,15 +59,16 @@ pub fn solve_3(a: f64, b: f64, c: f64) -> Result<SVector<f64, 2>, &'static str>
     let det = (b * b) - 4.0 * a * c;
 
     if det < 0.0 {
-        return Err("No solution")
+        return Err(DetectorError::NoSolution)
     }
 
     //auto sqrtdet = sqrt(det);
     let q = -0.5 * (b + (if b >= 0.0 { 1.0 } else { -1.0 }) * det.sqrt());
-    return Ok(Vector2::new(q / a, c / q));
+    Ok(Vector2::new(q / a, c / q))
 }
 
-pub fn solve_4(a: f64, b: f64, c: f64, d: f64) -> Result<Vector3<f64>, &'static str> {
+#[allow(non_snake_case)]
+pub fn solve_4(a: f64, b: f64, c: f64, d: f64) -> Result<Vector3<f64>, DetectorError> {
     if a == 0.0 {
         return match solve_3(b, c, d) {
             Ok(roots) => Ok(Vector3::new(roots[0], roots[1], roots[1])),
@@ -65,7 +88,7 @@ pub fn solve_4(a: f64, b: f64, c: f64, d: f64) -> Result<Vector3<f64>, &'static
     let j = 4.0 * u * u * u / 27.0 + v * v;
     let M = f64::MAX;
     let sqrtM = M.sqrt();
-    let cbrtM = M.sqrt();
+    let cbrtM = M.cbrt();
 
     if b == 0.0 && c == 0.0 {
         return Ok(Vector3::new(-d.cbrt(), -d.cbrt(), -d.cbrt()));
@@ -83,7 +106,8 @@ pub fn solve_4(a: f64, b: f64, c: f64, d: f64) -> Result<Vector3<f64>, &'static
         return Ok(Vector3::new(4.0_f64.cbrt() * u / 3.0, 4.0_f64.cbrt() * u / 3.0, 4.0_f64.cbrt() * u / 3.0));
     }
 
-    return if j > 0.0 {
+    // A double root leaves j at zero up to rounding, which must not hide the second real root
+    if j > 1e-12 * v * v {
         // One real root
         let w = j.sqrt();
         let y = if v > 0.0 {
@@ -95,8 +119,11 @@ pub fn solve_4(a: f64, b: f64, c: f64, d: f64) -> Result<Vector3<f64>, &'static
         Ok(Vector3::new(y, y, y))
     } else {
         // Three real roots
-        let s = (-u / 3.0).sqrt();
-        let t = -v / (2.0 * s * s * s);
+        let s = (-u / 3.0).max(0.0).sqrt();
+        if s == 0.0 {
+            return Ok(Vector3::new(-p / 3.0, -p / 3.0, -p / 3.0));
+        }
+        let t = (-v / (2.0 * s * s * s)).clamp(-1.0, 1.0);
         let k = t.acos() / 3.0;
         let y1 = 2.0 * s * k.cos() - p / 3.0;
         let y2 = s * (-k.cos() + 3.0_f64.sqrt() * k.sin()) - p / 3.0;
@@ -105,6 +132,7 @@ pub fn solve_4(a: f64, b: f64, c: f64, d: f64) -> Result<Vector3<f64>, &'static
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn unproject_conicoid(
     a: f64,
     b: f64,
@@ -117,56 +145,134 @@ pub fn unproject_conicoid(
     w: f64,
     focal_length: f64,
     circle_radius: f64,
-) {
-    let lambda: Vector3<f64> = solve_4(1., -(a + b + c), (b * c + c * a + a * b - f * f - g * g - h * h), -(a * b * c + 2.0 * f * g * h - a * f * f - b * g * g - c * h * h)).unwrap();
+) -> Option<[Circle3D; 2]> {
+    // Safaee-Rad 1992: get the canonical form lambda(1) X^2 + lambda(2) Y^2 + lambda(3) Z^2 = mu
+    // by solving the discriminating cubic (10). Sorting descending rules out the case of eq (30).
+    let mut lambda: Vector3<f64> = solve_4(1., -(a + b + c), b * c + c * a + a * b - f * f - g * g - h * h, -(a * b * c + 2.0 * f * g * h - a * f * f - b * g * g - c * h * h)).ok()?;
+    lambda.as_mut_slice().sort_by(|x, y| y.total_cmp(x));
+
+    if lambda[1] <= 0.0 || lambda[2] >= 0.0 {
+        return None
+    }
 
+    // Direction cosines of the plane lX + mY + nZ = p cutting the cone in a circle, eq (31)
     let n = ((lambda[1] - lambda[2]) / (lambda[0] - lambda[2])).sqrt();
     let m = 0.0;
     let l = ((lambda[0] - lambda[1]) / (lambda[0] - lambda[2])).sqrt();
 
-    let a: Vector3<f64> = Vector3::from_element(a);
-    let b: Vector3<f64> = Vector3::from_element(b);
-    let c: Vector3<f64> = Vector3::from_element(c);
-    let f: Vector3<f64> = Vector3::from_element(f);
-    let g: Vector3<f64> = Vector3::from_element(g);
-    let h: Vector3<f64> = Vector3::from_element(h);
-    let u: Vector3<f64> = Vector3::from_element(u);
-    let v: Vector3<f64> = Vector3::from_element(v);
-    let w: Vector3<f64> = Vector3::from_element(w);
-
-
-    let t1: Vector3<f64> = (lambda - b).component_mul(&g) - f.component_mul(&h);
-    let t2: Vector3<f64> = (a - lambda).component_mul(&f) - g.component_mul(&h);
-    let t3: Vector3<f64> = (-(a - lambda)).component_mul(&(t1.component_div(&t2)).component_div(&g)) - h.component_div(&g);
+    // T1 rotates the canonical conic frame back into image space, eq (8). The closed form for the
+    // direction cosines breaks down when t1 and t2 vanish, so take each eigenvector from the rows of
+    // (Q - lambda I) and complete a right-handed frame around the well separated negative eigenvalue.
+    let cone = Matrix3::new(
+        a, h, g,
+        h, b, f,
+        g, f, c
+    );
+    let eigenvector = |lambda: f64| -> Vector3<f64> {
+        let reduced = cone - Matrix3::identity() * lambda;
+        let (r0, r1, r2) = (reduced.row(0).transpose(), reduced.row(1).transpose(), reduced.row(2).transpose());
+        let candidates = [r0.cross(&r1), r0.cross(&r2), r1.cross(&r2)];
+        *candidates.iter().max_by(|x, y| x.norm_squared().total_cmp(&y.norm_squared())).unwrap()
+    };
+    let ni = eigenvector(lambda[2]).normalize();
+    let li = eigenvector(lambda[0]);
+    let li = li - ni * ni.dot(&li);
+    // A circular image has a repeated eigenvalue, any direction orthogonal to ni will do then
+    let li = if li.norm() > f64::EPSILON * lambda[0].abs() {
+        li.normalize()
+    } else {
+        let axis = if ni.x.abs() < 0.9 { Vector3::x() } else { Vector3::y() };
+        ni.cross(&axis).normalize()
+    };
+    let mi = ni.cross(&li);
+
+    let t1_matrix = Matrix3::from_columns(&[li, mi, ni]);
+
+    // T0 moves the cone vertex back to the camera origin, eq (34)
+    let t0_vector = Vector3::new(0.0, 0.0, focal_length);
+
+    // T2 translates from the canonical conic frame into image space in the canonical frame, eq (14)
+    let t2_vector: Vector3<f64> = -Vector3::new(
+        li.dot(&Vector3::new(u, v, w)),
+        mi.dot(&Vector3::new(u, v, w)),
+        ni.dot(&Vector3::new(u, v, w))
+    ).component_div(&lambda);
+
+    let solve_for = |l: f64| -> Circle3D {
+        let mut gaze: Vector3<f64> = t1_matrix * Vector3::new(l, m, n);
+
+        // T3 rotates the frame where Z is the circle normal into the canonical frame, eq (19) with m = 0
+        let t3_matrix = Matrix3::new(
+            0.0, -n * l.signum(), l,
+            l.signum(), 0.0, 0.0,
+            0.0, l.abs(), n
+        );
+
+        // Circle center, eq (38) and (41)
+        let col0 = t3_matrix.column(0);
+        let col1 = t3_matrix.column(1);
+        let col2 = t3_matrix.column(2);
+        let a_ = lambda.dot(&col0.component_mul(&col0));
+        let b_ = lambda.dot(&col0.component_mul(&col2));
+        let c_ = lambda.dot(&col1.component_mul(&col2));
+        let d_ = lambda.dot(&col2.component_mul(&col2));
+
+        let mut center_in_x_prime = Vector3::zeros();
+        center_in_x_prime[2] = a_ * circle_radius / (b_ * b_ + c_ * c_ - a_ * d_).sqrt();
+        center_in_x_prime[0] = -b_ / a_ * center_in_x_prime[2];
+        center_in_x_prime[1] = -c_ / a_ * center_in_x_prime[2];
+
+        // eq (42) using (35)
+        let mut center: Vector3<f64> = t0_vector + t1_matrix * (t2_vector + t3_matrix * center_in_x_prime);
+
+        // The other root of eq (41) lies in front of the camera
+        if center[2] < 0.0 {
+            center_in_x_prime = -center_in_x_prime;
+            center = t0_vector + t1_matrix * (t2_vector + t3_matrix * center_in_x_prime);
+        }
 
-    let mut mi = Vector3::from_element(1.0).component_div(&(Vector3::from_element(1.0) + (t1.component_div(&t2)).map(|x| x.sqrt()) + t3.map(|x| x.sqrt())));
-    let mut li = (t1.component_div(&t2)).component_mul(&mi);
-    let mut ni = t3.component_mul(&mi);
+        // The gaze points towards the camera
+        if gaze.dot(&center) > 0.0 {
+            gaze = -gaze;
+        }
+        gaze.normalize_mut();
 
-    let t1 = (b - lambda).component_mul(&g) - f.component_mul(&h);
-    let t2 = (a - lambda).component_mul(&f) - g.component_mul(&h);
-    let t3 = (-(a - lambda)).component_mul(&(t1.component_div(&t2).component_div(&g) - h.component_div(&g)));
-    mi = Vector3::from_element(1.0).component_div(&((Vector3::from_element(1.0) + (t1.component_div(&t2)).map(|x| x.powf(2.0)) + t3.map(|x| x.powf(2.0))).map(|x| x.sqrt())));
-    li = t1.component_div(&t2).component_mul(&mi);
-    ni = t3.component_mul(&mi);
+        Circle3D {
+            center: array!(center[0], center[1], center[2]),
+            normal: array!(gaze[0], gaze[1], gaze[2]),
+            radius: circle_radius
+        }
+    };
 
-    if li.cross(&mi).dot(&ni) < 0.0 {
-        li = -li;
-        mi = -mi;
-        ni = -ni;
+    let circles = [solve_for(l), solve_for(-l)];
+    if circles.iter().any(|circle| circle.center.iter().chain(circle.normal.iter()).any(|x| !x.is_finite())) {
+        return None
     }
 
-
+    Some(circles)
 }
 
 pub fn unproject_ellipse(ellipse: &Ellipse, focal_length: f64, radius: f64) -> Option<[Circle3D; 2]> {
+    if ellipse.minor_radius.is_nan() || ellipse.minor_radius <= 0.0 || !ellipse.center.iter().all(|x| x.is_finite()) {
+        return None
+    }
+
     let conic = Conic::new(ellipse);
     let pupil_cone = Conicoid::new(conic, array!(0.0, 0.0, -focal_length));
 
-    //let circles = unproject_conicoid(
-
-    //)
-    todo!()
+    unproject_conicoid(
+        pupil_cone.a,
+        pupil_cone.b,
+        pupil_cone.c,
+        pupil_cone.f,
+        pupil_cone.g,
+        pupil_cone.h,
+        pupil_cone.u,
+        pupil_cone.v,
+        pupil_cone.w,
+        focal_length,
+        radius
+    )
 }
 
 pub fn project_point_into_image_plane(point: Array1<f64>, focal_length: f64) -> Array1<f64> {
@@ -175,12 +281,128 @@ pub fn project_point_into_image_plane(point: Array1<f64>, focal_length: f64) ->
     point_projected.slice(s![..2]).to_owned()
 }
 
-pub fn project_line_onto_image_plane(line: Line, focal_length: f64) -> Line {
+// A line through the camera center projects to a point and has no direction
+pub fn project_line_onto_image_plane(line: Line, focal_length: f64) -> Option<Line> {
     let p1 = line.origin.clone();
     let p2 = line.origin + line.direction;
 
     let p1_projected = project_point_into_image_plane(p1, focal_length);
     let p2_projected = project_point_into_image_plane(p2, focal_length);
 
-    Line::new(p1_projected.clone(), p2_projected - p1_projected)
-}
\ No newline at end of file
+    Line::try_new(p1_projected.clone(), p2_projected - p1_projected)
+}
+
+pub fn project_sphere_into_image_plane(sphere: &Sphere, focal_length: f64) -> Ellipse {
+    let scale = focal_length / sphere.center[2];
+    let projected_center = scale * &sphere.center;
+    let projected_radius = scale * sphere.radius;
+    Ellipse::new(projected_center.slice(s![..2]).to_owned(), projected_radius, projected_radius, 0.0)
+}
+
+pub fn project_circle_into_image_plane(circle: &Circle3D, focal_length: f64) -> Option<Ellipse> {
+    let c = &circle.center;
+    let n = &circle.normal;
+    let r = circle.radius;
+    let f = focal_length;
+
+    let cn = n.dot(c);
+    let c2r2 = c.dot(c) - r * r;
+    let abc = cn * cn - 2.0 * cn * (c * n) + c2r2 * (n * n);
+    let f_ = 2.0 * (c2r2 * n[1] * n[2] - cn * (n[1] * c[2] + n[2] * c[1]));
+    let g_ = 2.0 * (c2r2 * n[2] * n[0] - cn * (n[2] * c[0] + n[0] * c[2]));
+    let h_ = 2.0 * (c2r2 * n[0] * n[1] - cn * (n[0] * c[1] + n[1] * c[0]));
+
+    let conic = Conic {
+        a: abc[0],
+        b: h_,
+        c: abc[1],
+        d: g_ * f,
+        e: f_ * f,
+        f: abc[2] * f * f
+    };
+
+    let disc = conic.discriminant();
+    if disc >= 0.0 {
+        return None
+    }
+
+    let (a, b, c, d, e, f) = (conic.a, conic.b, conic.c, conic.d, conic.e, conic.f);
+    let center_x = (2.0 * c * d - b * e) / disc;
+    let center_y = (2.0 * a * e - b * d) / disc;
+    let temp = 2.0 * (a * e * e + c * d * d - b * d * e + disc * f);
+    let root = ((a - c).powi(2) + b * b).sqrt();
+    let minor_radius = -(temp * (a + c - root)).abs().sqrt() / disc;
+    let major_radius = -(temp * (a + c + root)).abs().sqrt() / disc;
+    let angle = if b == 0.0 {
+        if a < c { 0.0 } else { PI / 2.0 }
+    } else {
+        ((c - a - root) / b).atan()
+    };
+
+    Some(Ellipse::new(array!(center_x, center_y), minor_radius, major_radius, angle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_through_camera_center_has_no_projection() {
+        let line = Line::new(array![0.0, 0.0, 5.0], array![0.0, 0.0, 1.0]);
+        assert!(project_line_onto_image_plane(line, 620.0).is_none());
+
+        let line = Line::new(array![1.0, 2.0, 50.0], array![0.0, 0.6, 0.8]);
+        let projected = project_line_onto_image_plane(line, 620.0).unwrap();
+        assert!((projected.direction.dot(&projected.direction) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn zero_length_direction_is_rejected() {
+        assert!(Line::try_new(array![1.0, 2.0, 3.0], array![0.0, 0.0, 0.0]).is_none());
+        assert!(Line::try_new(array![f64::NAN, 2.0, 3.0], array![0.0, 0.0, 1.0]).is_none());
+    }
+
+    fn assert_same_ellipse(actual: &Ellipse, expected: &Ellipse) {
+        assert!((&actual.center - &expected.center).iter().all(|x| x.abs() < 1e-6), "center {} expected {}", actual.center, expected.center);
+        assert!((actual.minor_radius - expected.minor_radius).abs() < 1e-6);
+        assert!((actual.major_radius - expected.major_radius).abs() < 1e-6);
+        // Orientation is only defined up to half a turn
+        let angle_difference = (actual.angle - expected.angle).rem_euclid(PI);
+        assert!(angle_difference.min(PI - angle_difference) < 1e-6, "angle {} expected {}", actual.angle, expected.angle);
+    }
+
+    #[test]
+    fn unprojected_circles_project_back_onto_the_ellipse() {
+        let focal_length = 620.0;
+        for ellipse in [
+            Ellipse::new(array![40.0, -25.0], 8.0, 10.0, 0.3),
+            Ellipse::new(array![-120.0, 60.0], 12.0, 20.0, 2.1),
+            Ellipse::new(array![5.0, 90.0], 3.0, 9.0, -0.7)
+        ] {
+            let circle_3d_pair = unproject_ellipse(&ellipse, focal_length, 2.0).unwrap();
+            for circle in &circle_3d_pair {
+                assert!((circle.radius - 2.0).abs() < 1e-9);
+                assert_same_ellipse(&project_circle_into_image_plane(circle, focal_length).unwrap(), &ellipse);
+            }
+        }
+    }
+
+    #[test]
+    fn projected_circle_unprojects_to_itself() {
+        let focal_length = 620.0;
+        let normal: Array1<f64> = array![0.3, -0.2, -0.9];
+        let circle = Circle3D {
+            center: array![4.0, -3.0, 40.0],
+            normal: &normal / normal.dot(&normal).sqrt(),
+            radius: 2.5
+        };
+        let ellipse = project_circle_into_image_plane(&circle, focal_length).unwrap();
+
+        let circle_3d_pair = unproject_ellipse(&ellipse, focal_length, circle.radius).unwrap();
+        let matches = circle_3d_pair.iter().filter(|candidate| {
+            (&candidate.center - &circle.center).iter().all(|x| x.abs() < 1e-6)
+                && (&candidate.normal - &circle.normal).iter().all(|x| x.abs() < 1e-6)
+        }).count();
+        assert_eq!(matches, 1);
+    }
+}
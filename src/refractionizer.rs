@@ -1,152 +1,197 @@
-use std::fs::File;
-use std::io::{Cursor, Read};
-use ndarray::{Array, Array2, ArrayView, Ix2};
-use serde_derive::Deserialize;
+use std::path::Path;
+use nalgebra::{DMatrix, DVector};
+use ndarray::{concatenate, s, Array1, Array2, ArrayView2, Axis};
+use serde_derive::{Deserialize, Serialize};
+use crate::error::DetectorError;
+use crate::pipeline::{Pipeline, PolynomialRegression};
 
 pub struct Refractionizer {
-    pub pipeline_radius_as_list: Steps,
-    pub pipeline_gaze_vector_as_list: Steps,
-    pub pipeline_sphere_center_as_list: Steps,
-    pub pipeline_pupil_circle_as_list: Steps
+    pub pipeline_radius_as_list: Pipeline,
+    pub pipeline_gaze_vector_as_list: Pipeline,
+    pub pipeline_sphere_center_as_list: Pipeline,
+    pub pipeline_pupil_circle_as_list: Pipeline
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
-pub struct LinearRegressionParams {
-    pub copy_X: bool,
-    pub fit_intercept: bool,
-    pub n_jobs: Option<u8>,
-    pub normalize: bool,
-    pub positive: bool
+#[derive(Deserialize)]
+pub struct Root {
+    pub version: u8,
+    pub steps: Pipeline
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
-pub struct LinearRegression {
-    pub params: LinearRegressionParams,
-    #[serde(with = "serde_ndim")]
-    pub coef: Array2<f64>,
-    #[serde(with = "serde_ndim")]
-    pub intercept: Array2<f64>,
+#[derive(Serialize)]
+struct RootRef<'a> {
+    version: u8,
+    steps: &'a PolynomialRegression
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
-pub struct StandardScalerParams {
-    pub copy: bool,
-    pub with_mean: bool,
-    pub with_std: bool
-}
+impl Refractionizer {
+    pub fn new() -> Result<Refractionizer, DetectorError> {
+        Self::load("default", 3, None)
+    }
 
-#[derive(Debug, PartialEq, Deserialize)]
-pub struct StandardScaler {
-    pub params: StandardScalerParams,
-    #[serde(with = "serde_ndim")]
-    pub mean: Array2<f64>,
-    #[serde(with = "serde_ndim")]
-    pub var: Array2<f64>
-}
+    pub fn load(type_: &str, degree: i8, custom_load_dir: Option<&str>) -> Result<Refractionizer, DetectorError> {
+        Ok(Refractionizer{
+            pipeline_radius_as_list: Self::load_config_from_msgpack("radius", type_, degree, custom_load_dir)?,
+            pipeline_gaze_vector_as_list: Self::load_config_from_msgpack("gaze_vector", type_, degree, custom_load_dir)?,
+            pipeline_sphere_center_as_list: Self::load_config_from_msgpack("sphere_center", type_, degree, custom_load_dir)?,
+            pipeline_pupil_circle_as_list: Self::load_config_from_msgpack("pupil_circle", type_, degree, custom_load_dir)?
+        })
+    }
 
-#[derive(Debug, PartialEq, Deserialize)]
-pub struct PolynomialParams {
-    pub degree: u8,
-    pub include_bias: bool,
-    pub interaction_only: bool,
-    pub order: String
-}
+    pub fn load_config_from_msgpack(feature: &str, type_: &str, degree: i8, custom_load_dir: Option<&str>) -> Result<Pipeline, DetectorError> {
+        let resolved_name = format!("{}_refraction_model_{}_degree_{}.msgpack", type_, feature, degree);
+        let buf = match custom_load_dir {
+            Some(load_dir) => std::fs::read(Path::new(load_dir).join(&resolved_name))?,
+            None => Self::embedded_model(&resolved_name)
+                .ok_or_else(|| DetectorError::ModelNotFound(resolved_name.clone()))?
+                .to_vec()
+        };
+        let root: Root = rmp_serde::decode::from_slice(&buf)?;
+        if root.version != 1 {
+            return Err(DetectorError::UnsupportedModelVersion(root.version));
+        }
 
-#[derive(Debug, PartialEq, Deserialize)]
-pub struct PolynomialFeatures {
-    pub params: PolynomialParams,
-    #[serde(with = "serde_ndim")]
-    pub powers: Array2<f64>,
-}
+        // Catch models trained on other inputs here instead of on the first frame
+        if let Some(n_inputs) = Self::n_inputs(feature).or(root.steps.n_features_in()) {
+            root.steps.check_input(n_inputs)?;
+        }
+        Ok(root.steps)
+    }
 
-#[derive(Debug, PartialEq, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-pub struct Steps {
-    pub polynomial_features: PolynomialFeatures,
-    pub standard_scaler: StandardScaler,
-    pub linear_regression: LinearRegression
-}
+    // Sphere center models map a sphere center, the others take (sphere center, gaze vector, radius)
+    fn n_inputs(feature: &str) -> Option<usize> {
+        match feature {
+            "sphere_center" => Some(3),
+            "radius" | "gaze_vector" | "pupil_circle" => Some(7),
+            _ => None
+        }
+    }
 
-#[derive(Debug, PartialEq, Deserialize)]
-pub struct Root {
-    pub version: u8,
-    pub steps: Steps
-}
+    pub fn save_config_to_msgpack(model: &PolynomialRegression, feature: &str, type_: &str, save_dir: &str) -> Result<(), DetectorError> {
+        let degree = model.polynomial_features.params.degree;
+        let resolved_name = format!("{}_refraction_model_{}_degree_{}.msgpack", type_, feature, degree);
+        let buf = rmp_serde::encode::to_vec_named(&RootRef { version: 1, steps: model })?;
+        std::fs::write(Path::new(save_dir).join(resolved_name), buf)?;
+        Ok(())
+    }
 
-impl Refractionizer {
-    pub fn new() -> Refractionizer {
-        let degree = 3;
-        Refractionizer{
-            pipeline_radius_as_list: Self::load_config_from_msgpack("radius", "default", degree, None),
-            pipeline_gaze_vector_as_list: Self::load_config_from_msgpack("gaze_vector", "default", degree, None),
-            pipeline_sphere_center_as_list: Self::load_config_from_msgpack("sphere_center", "default", degree, None),
-            pipeline_pupil_circle_as_list: Self::load_config_from_msgpack("pupil_circle", "default", degree, None)
+    // The default models ship inside the binary so no files have to be installed next to it
+    fn embedded_model(resolved_name: &str) -> Option<&'static [u8]> {
+        match resolved_name {
+            "default_refraction_model_radius_degree_3.msgpack" =>
+                Some(include_bytes!("../default_refraction_model_radius_degree_3.msgpack")),
+            "default_refraction_model_gaze_vector_degree_3.msgpack" =>
+                Some(include_bytes!("../default_refraction_model_gaze_vector_degree_3.msgpack")),
+            "default_refraction_model_sphere_center_degree_3.msgpack" =>
+                Some(include_bytes!("../default_refraction_model_sphere_center_degree_3.msgpack")),
+            "default_refraction_model_pupil_circle_degree_3.msgpack" =>
+                Some(include_bytes!("../default_refraction_model_pupil_circle_degree_3.msgpack")),
+            _ => None
         }
     }
 
-    pub fn load_config_from_msgpack(feature: &str, type_: &str, degree: i8, custom_load_dir: Option<&str>) -> Steps {
-        let resolved_name = format!("{}_refraction_model_{}_degree_{}.msgpack", type_, feature, degree);
-        let mut path = "C:\\Users\\Ben\\RustroverProjects\\rs3d-detector\\".to_owned();
-        path.push_str(&resolved_name);
-        let f = File::open(path);
-        let mut buf = Vec::new();
-        f.unwrap().read_to_end(&mut buf).unwrap();
-        let cursor = Cursor::new(buf);
-        let root: Root = rmp_serde::decode::from_read(cursor).unwrap();
-        // Ensure version is 1
-        root.steps
-    }
-
-    fn apply_correction_pipeline(
-        x: ArrayView<f64, Ix2>,
-        powers: &Array2<f64>,
-        mean: &Array2<f64>,
-        var: &Array2<f64>,
-        coef: &Array2<f64>,
-        intercept: &Array2<f64>,
-    ) -> Array2<f64> {
-        let mut features: Array<f64, _> = Array::<f64, _>::zeros((powers.len(), x.len()));
-
-        for k in 0..x.len() {
-            for i in 0..powers.len() {
-                features[[i, k]] = 1.0;
-                for j in 0..powers.len() {
-                    for _ in 0..powers.len() {
-                        features[[i, k]] *= x[[k, j]];
-                    }
+    pub fn correct_radius(&self, x: Array2<f64>) -> Result<Array2<f64>, DetectorError> {
+        self.pipeline_radius_as_list.predict(x.view())
+    }
+
+    pub fn correct_gaze_vector(&self, x: Array2<f64>) -> Result<Array2<f64>, DetectorError> {
+        self.pipeline_gaze_vector_as_list.predict(x.view())
+    }
+
+    pub fn correct_sphere_center(&self, x: Array2<f64>) -> Result<Array2<f64>, DetectorError> {
+        self.pipeline_sphere_center_as_list.predict(x.view())
+    }
+
+    pub fn correct_pupil_circle(&self, x: Array2<f64>) -> Result<Array2<f64>, DetectorError> {
+        self.pipeline_pupil_circle_as_list.predict(x.view())
+    }
+
+    // Apparent sphere centers that correct_sphere_center maps onto the given corrected ones
+    pub fn uncorrect_sphere_center(&self, x: Array2<f64>) -> Result<Array2<f64>, DetectorError> {
+        Self::invert_pipeline(&self.pipeline_sphere_center_as_list, Array2::zeros((x.nrows(), 0)).view(), x.view())
+    }
+
+    // Rows of (apparent sphere center, corrected gaze vector, corrected radius) to (apparent gaze vector, apparent radius)
+    pub fn uncorrect_pupil_circle(&self, x: Array2<f64>) -> Result<Array2<f64>, DetectorError> {
+        Self::invert_pipeline(&self.pipeline_pupil_circle_as_list, x.slice(s![.., ..3]), x.slice(s![.., 3..]))
+    }
+
+    // Solves pipeline([fixed, unknown]) = target row by row with Gauss-Newton. Refraction is a small
+    // perturbation, so the target itself is a good starting point.
+    fn invert_pipeline(pipeline: &Pipeline, fixed: ArrayView2<f64>, target: ArrayView2<f64>) -> Result<Array2<f64>, DetectorError> {
+        let n_unknowns = target.ncols();
+        let mut solution = target.to_owned();
+
+        for (i, mut unknown) in solution.axis_iter_mut(Axis(0)).enumerate() {
+            let target = DVector::from_iterator(n_unknowns, target.row(i).iter().copied());
+            for _ in 0..20 {
+                // Evaluate the current estimate and one forward difference per unknown in a single batch
+                let step = 1e-6;
+                let mut unknowns = Array2::from_shape_fn((n_unknowns + 1, n_unknowns), |(_, j)| unknown[j]);
+                for j in 0..n_unknowns {
+                    unknowns[[j + 1, j]] += step;
+                }
+                let fixed_rows = fixed.row(i).insert_axis(Axis(0)).broadcast((n_unknowns + 1, fixed.ncols())).unwrap().to_owned();
+                let y = pipeline.predict(concatenate![Axis(1), fixed_rows, unknowns].view())?;
+
+                let residual = DVector::from_iterator(n_unknowns, y.row(0).iter().copied()) - &target;
+                let jacobian = DMatrix::from_fn(n_unknowns, n_unknowns, |k, j| (y[[j + 1, k]] - y[[0, k]]) / step);
+                let delta = match jacobian.svd(true, true).solve(&-residual, 1e-12) {
+                    Ok(delta) => delta,
+                    Err(_) => break
+                };
+
+                unknown += &Array1::from_iter(delta.iter().copied());
+                if delta.norm() < 1e-12 {
+                    break;
                 }
-                features[[i, k]] -= mean[[0, i]];
-                features[[i, k]] /= var[[0, i]].sqrt();
             }
         }
 
-        ((*&coef * &features).reversed_axes() + &intercept.column(0)).t().to_owned()
+        Ok(solution)
     }
+}
 
-    fn _apply_correction_pipeline(x: Array2<f64>, pipeline_arrays: &Steps) -> Array2<f64> {
-        Self::apply_correction_pipeline(
-            x.t(),
-            &pipeline_arrays.polynomial_features.powers,
-            &pipeline_arrays.standard_scaler.mean,
-            &pipeline_arrays.standard_scaler.var,
-            &pipeline_arrays.linear_regression.coef,
-            &pipeline_arrays.linear_regression.intercept
-        )
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn correct_radius(&self, x: Array2<f64>) -> Array2<f64> {
-        Self::_apply_correction_pipeline(x, &self.pipeline_radius_as_list)
+    #[test]
+    fn default_models_accept_their_inputs() {
+        let refractionizer = Refractionizer::new().unwrap();
+        assert_eq!(refractionizer.correct_sphere_center(Array2::zeros((2, 3))).unwrap().shape(), &[2, 3]);
+        assert_eq!(refractionizer.correct_pupil_circle(Array2::zeros((2, 7))).unwrap().shape(), &[2, 4]);
+        assert!(matches!(refractionizer.correct_pupil_circle(Array2::zeros((2, 3))), Err(DetectorError::InvalidInput(_))));
     }
 
-    pub fn correct_gaze_vector(&self, x: Array2<f64>) -> Array2<f64> {
-        Self::_apply_correction_pipeline(x, &self.pipeline_gaze_vector_as_list)
-    }
+    #[test]
+    fn loading_rejects_models_trained_on_other_inputs() {
+        let dir = std::env::temp_dir().join(format!("rs3d_refraction_inputs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
 
-    pub fn correct_sphere_center(&self, x: Array2<f64>) -> Array2<f64> {
-        Self::_apply_correction_pipeline(x, &self.pipeline_sphere_center_as_list)
+        let x = Array2::from_shape_fn((20, 2), |(i, j)| (i * (j + 1)) as f64 * 0.1);
+        let model = PolynomialRegression::fit(x.view(), x.view(), 1).unwrap();
+        Refractionizer::save_config_to_msgpack(&model, "sphere_center", "narrow", dir.to_str().unwrap()).unwrap();
+        let result = Refractionizer::load_config_from_msgpack("sphere_center", "narrow", 1, dir.to_str());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(DetectorError::InvalidInput(_))));
     }
 
-    pub fn correct_pupil_circle(&self, x: Array2<f64>) -> Array2<f64> {
-        Self::_apply_correction_pipeline(x, &self.pipeline_pupil_circle_as_list)
+    #[test]
+    fn saved_model_predicts_the_same_after_loading() {
+        let dir = std::env::temp_dir().join(format!("rs3d_refraction_round_trip_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let x = Array2::from_shape_fn((60, 7), |(i, j)| ((i * 7 + j) as f64 * 0.61).sin());
+        let y = Array2::from_shape_fn((60, 1), |(i, _)| x.row(i).sum() + x[[i, 0]] * x[[i, 6]]);
+        let model = PolynomialRegression::fit(x.view(), y.view(), 2).unwrap();
+        Refractionizer::save_config_to_msgpack(&model, "radius", "round_trip", dir.to_str().unwrap()).unwrap();
+        let loaded = Refractionizer::load_config_from_msgpack("radius", "round_trip", 2, dir.to_str());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let loaded = loaded.unwrap();
+        let expected = model.into_pipeline().predict(x.view()).unwrap();
+        assert_eq!(loaded.predict(x.view()).unwrap(), expected);
     }
-}
\ No newline at end of file
+}
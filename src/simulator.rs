@@ -0,0 +1,217 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+use nalgebra::{Matrix2, Matrix3x2, Vector2, Vector3};
+use ndarray::{array, Array1, Array2};
+use crate::CameraModel::CameraModel;
+use crate::Detector3D::{PupilDatum, PupilEllipse};
+use crate::eye_parameters::EyeParameters;
+use crate::primitive::Ellipse;
+use crate::projections::Circle3D;
+
+pub struct EyeModel {
+    pub sphere_center: Array1<f64>,
+    // The cornea bulges out of the eyeball beyond this radius in mm
+    pub eyeball_radius: f64,
+    pub parameters: EyeParameters
+}
+
+pub struct SimulatedObservation {
+    pub ellipse: Ellipse,
+    pub pupil_circle: Circle3D,
+    pub gaze_vector: Array1<f64>,
+    pub sphere_center: Array1<f64>
+}
+
+pub struct EyeSimulator {
+    pub camera: Arc<CameraModel>,
+    pub eye: EyeModel,
+    pub n_contour_points: usize
+}
+
+struct Ray {
+    origin: Vector3<f64>,
+    direction: Vector3<f64>
+}
+
+impl EyeModel {
+    pub fn new(sphere_center: Array1<f64>, parameters: EyeParameters) -> EyeModel {
+        EyeModel {
+            sphere_center,
+            eyeball_radius: 12.0,
+            parameters
+        }
+    }
+
+    // LeGrand schematic eye with a single refracting corneal surface
+    pub fn le_grand(sphere_center: Array1<f64>) -> EyeModel {
+        Self::new(sphere_center, EyeParameters::le_grand())
+    }
+}
+
+impl EyeSimulator {
+    pub fn new(camera: Arc<CameraModel>, eye: EyeModel) -> EyeSimulator {
+        EyeSimulator {
+            camera,
+            eye,
+            n_contour_points: 64
+        }
+    }
+
+    // The gaze vector points out of the eye, i.e. towards the camera when looking at it
+    pub fn simulate(&self, gaze_vector: &Array1<f64>, pupil_radius: f64) -> Option<SimulatedObservation> {
+        let sphere_center = to_vector(&self.eye.sphere_center);
+        let gaze = to_vector(gaze_vector).normalize();
+        let pupil_center = sphere_center + self.eye.parameters.pupil_distance * gaze;
+
+        // Any orthonormal basis of the pupil plane will do
+        let axis = if gaze.y.abs() < 0.9 { Vector3::y() } else { Vector3::x() };
+        let u = gaze.cross(&axis).normalize();
+        let v = gaze.cross(&u);
+
+        let mut contour = Array2::zeros((self.n_contour_points, 2));
+        for i in 0..self.n_contour_points {
+            let t = 2.0 * PI * i as f64 / self.n_contour_points as f64;
+            let point = pupil_center + pupil_radius * (t.cos() * u + t.sin() * v);
+            let image_point = self.apparent_image_point(&gaze, &point)?;
+            contour[[i, 0]] = image_point[0];
+            contour[[i, 1]] = image_point[1];
+        }
+
+        Some(SimulatedObservation {
+            ellipse: Ellipse::fit(contour.view())?,
+            pupil_circle: Circle3D {
+                center: from_vector(&pupil_center),
+                normal: from_vector(&gaze),
+                radius: pupil_radius
+            },
+            gaze_vector: from_vector(&gaze),
+            sphere_center: self.eye.sphere_center.clone()
+        })
+    }
+
+    // Point in the internal image frame at which a point inside the eye appears through the cornea
+    pub fn apparent_image_point(&self, gaze: &Vector3<f64>, point: &Vector3<f64>) -> Option<Vector2<f64>> {
+        let focal_length = self.camera.focal_length();
+        let residual = |image_point: &Vector2<f64>| -> Option<Vector3<f64>> {
+            let ray = self.trace(gaze, &Vector3::new(image_point.x, image_point.y, focal_length))?;
+            let offset = point - ray.origin;
+            Some(offset - ray.direction * offset.dot(&ray.direction))
+        };
+
+        // Gauss-Newton on the image point, starting from the unrefracted projection
+        let mut image_point = Vector2::new(focal_length * point.x / point.z, focal_length * point.y / point.z);
+        let step = 1e-6 * focal_length;
+        for _ in 0..50 {
+            let r = residual(&image_point)?;
+            let jacobian = Matrix3x2::from_columns(&[
+                (residual(&(image_point + Vector2::new(step, 0.0)))? - r) / step,
+                (residual(&(image_point + Vector2::new(0.0, step)))? - r) / step
+            ]);
+            let normal_matrix: Matrix2<f64> = jacobian.transpose() * jacobian;
+            let delta = normal_matrix.try_inverse()? * (jacobian.transpose() * -r);
+            image_point += delta;
+            if delta.norm() < 1e-10 * focal_length {
+                break;
+            }
+        }
+
+        if residual(&image_point)?.norm() > 1e-6 {
+            return None
+        }
+
+        Some(image_point)
+    }
+
+    // Refracts a camera ray at the cornea, None if it misses the corneal cap
+    fn trace(&self, gaze: &Vector3<f64>, direction: &Vector3<f64>) -> Option<Ray> {
+        let sphere_center = to_vector(&self.eye.sphere_center);
+        let cornea_center = sphere_center + self.eye.parameters.cornea_center_distance * gaze;
+        let direction = direction.normalize();
+
+        let closest_approach = direction.dot(&cornea_center);
+        let delta = closest_approach.powi(2) - cornea_center.norm_squared() + self.eye.parameters.cornea_radius.powi(2);
+        if delta < 0.0 {
+            return None
+        }
+        let hit = (closest_approach - delta.sqrt()) * direction;
+
+        // The cornea only bulges out of the eyeball in front of the limbus
+        if (hit - sphere_center).norm() < self.eye.eyeball_radius || (hit - sphere_center).dot(gaze) < 0.0 {
+            return None
+        }
+
+        // Snell's law with the outward surface normal
+        let normal = (hit - cornea_center) / self.eye.parameters.cornea_radius;
+        let eta = 1.0 / self.eye.parameters.refractive_index;
+        let cos_incidence = -normal.dot(&direction);
+        let k = 1.0 - eta * eta * (1.0 - cos_incidence * cos_incidence);
+        if k < 0.0 {
+            return None
+        }
+
+        Some(Ray {
+            origin: hit,
+            direction: (eta * direction + (eta * cos_incidence - k.sqrt()) * normal).normalize()
+        })
+    }
+
+    // Same layout as the 2D detector output that Detector3D consumes
+    pub fn pupil_datum(&self, observation: &SimulatedObservation, confidence: f64, timestamp: f64) -> PupilDatum {
+        PupilDatum {
+            confidence,
+            timestamp,
+            ellipse: PupilEllipse::from_ellipse(&self.camera.internal_ellipse_to_image(&observation.ellipse))
+        }
+    }
+}
+
+fn to_vector(a: &Array1<f64>) -> Vector3<f64> {
+    Vector3::new(a[0], a[1], a[2])
+}
+
+fn from_vector(v: &Vector3<f64>) -> Array1<f64> {
+    array!(v.x, v.y, v.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{ArrayD, IxDyn};
+    use crate::Detector3D::Detector3D;
+    use crate::utils::sph2cart;
+
+    fn camera() -> CameraModel {
+        CameraModel::new(620.0, array![400.0, 400.0])
+    }
+
+    // Gaze wandering around the camera direction
+    fn gaze(i: usize) -> Array1<f64> {
+        let phi = -PI / 2.0 + 0.5 * (i as f64 * 0.77).sin();
+        let theta = PI / 2.0 + 0.4 * (i as f64 * 1.31).cos();
+        sph2cart(phi, theta)
+    }
+
+    #[test]
+    fn detector_converges_on_simulated_eye() {
+        let sphere_center = array![3.0, -2.0, 40.0];
+        let simulator = EyeSimulator::new(Arc::new(camera()), EyeModel::le_grand(sphere_center.clone()));
+        let mut detector = Detector3D::new(camera(), None, None, Some(simulator.eye.parameters.clone())).unwrap();
+
+        let mut errors = Vec::new();
+        for i in 0..400 {
+            let observation = simulator.simulate(&gaze(i), 2.0).unwrap();
+            let pupil_datum = simulator.pupil_datum(&observation, 0.99, i as f64 * 0.05);
+            let result = detector.update_and_detect(pupil_datum, ArrayD::zeros(IxDyn(&[1])), true, false).unwrap();
+            let offset = &result.sphere.center - &sphere_center;
+            errors.push(offset.dot(&offset).sqrt());
+        }
+
+        // The refraction models leave a bias of a few tenths of a mm, but the estimate has to settle
+        let settled = &errors[100..];
+        let max_error = settled.iter().cloned().fold(0.0, f64::max);
+        let min_error = settled.iter().cloned().fold(f64::INFINITY, f64::min);
+        assert!(errors[0] > 5.0);
+        assert!(max_error < 0.6, "sphere center off by {} mm", max_error);
+        assert!(max_error - min_error < 0.05, "sphere center still moves by {} mm", max_error - min_error);
+    }
+}
@@ -1,78 +1,277 @@
-use ndarray::{array, Array1, Array2, Axis, stack};
+use std::sync::Arc;
+use nalgebra::{Matrix2, Matrix3, Vector2, Vector3};
+use ndarray::{array, concatenate, s, Array1, Array2, Axis};
 use crate::CameraModel::CameraModel;
-use crate::observations::{Observation, ObservationStorage};
+use crate::error::DetectorError;
+use crate::eye_parameters::{EyeParameters, RefractionModelSet};
+use crate::observations::{FitLines, Observation, ObservationStorage};
+use crate::primitive::Ellipse;
+use crate::projections::{Circle3D, project_circle_into_image_plane};
 use crate::refractionizer::Refractionizer;
 
+pub const SPHERE_RADIUS_DEFAULT: f64 = 10.392304845413264;
+
+pub struct SphereCenterEstimate {
+    pub projected_sphere_center: Array1<f64>,
+    pub sphere_center: Array1<f64>,
+    pub rms_residual: f64
+}
+
 pub struct TwoSphereModel {
-    pub camera: *const CameraModel,
+    pub camera: Arc<CameraModel>,
+    pub eye_parameters: EyeParameters,
     pub refractionizer: Refractionizer,
     pub storage: Box<dyn ObservationStorage>,
     pub sphere_center: Array1<f64>,
     pub corrected_sphere_center: Array1<f64>,
-    pub projected_sphere_center: f64,
-    pub rms_residual: f64
+    pub projected_sphere_center: Array1<f64>,
+    pub rms_residual: f64,
+    // Until the first fit the centers are only defaults and make poor priors
+    pub has_fit: bool
 }
 
 impl TwoSphereModel {
-    pub fn new(camera: *const CameraModel, storage: Box<dyn ObservationStorage>) -> TwoSphereModel {
+    pub fn new(camera: Arc<CameraModel>, eye_parameters: EyeParameters, storage: Box<dyn ObservationStorage>) -> Result<TwoSphereModel, DetectorError> {
+        // Without any model set the embedded default models are used
+        let model_set = eye_parameters.refraction_model_set().cloned()
+            .unwrap_or_else(|| RefractionModelSet::trained_on("default", 3, None, &eye_parameters));
+        let refractionizer = Refractionizer::load(&model_set.type_, model_set.degree, model_set.custom_load_dir.as_deref())?;
         let mut model = TwoSphereModel {
             camera,
+            eye_parameters,
             storage,
-            refractionizer: Refractionizer::new(),
+            refractionizer,
             sphere_center: Array1::zeros(3),
             corrected_sphere_center: Array1::zeros(3),
-            projected_sphere_center: 25.0,
-            rms_residual: f64::NAN
+            projected_sphere_center: Array1::zeros(2),
+            rms_residual: f64::NAN,
+            has_fit: false
         };
 
-        model.set_default_model_params();
+        model.set_default_model_params()?;
 
-        model
+        Ok(model)
     }
 
-    fn set_default_model_params(&mut self) {
-        self.sphere_center = array!(0.0, 0.0, 35.0);
-        self.corrected_sphere_center = self.refractionizer.correct_sphere_center(self.sphere_center.to_owned().insert_axis(Axis(0))).row(0).to_owned();
+    fn set_default_model_params(&mut self) -> Result<(), DetectorError> {
+        self.set_sphere_center(array!(0.0, 0.0, 35.0))?;
         self.rms_residual = f64::NAN;
+        self.has_fit = false;
+
+        Ok(())
     }
 
     pub fn add_observation(&mut self, observation: Observation) {
         self.storage.add(observation)
     }
 
-    pub fn set_sphere_center(&mut self, new_sphere_center: Array1<f64>) {
+    pub fn n_observations(&self) -> usize {
+        self.storage.count()
+    }
+
+    // Sphere centers are in mm and stay valid, the projected center lives in the internal frame
+    pub fn change_camera(&mut self, camera: Arc<CameraModel>, scale: f64) -> Result<(), DetectorError> {
+        self.storage.change_camera(&camera, scale)?;
+        self.camera = camera;
+        self.projected_sphere_center *= scale;
+
+        Ok(())
+    }
+
+    pub fn set_sphere_center(&mut self, new_sphere_center: Array1<f64>) -> Result<(), DetectorError> {
+        self.corrected_sphere_center = self.refractionizer.correct_sphere_center(new_sphere_center.to_owned().insert_axis(Axis(0)))?.row(0).to_owned();
         self.sphere_center = new_sphere_center;
-        self.corrected_sphere_center = self.refractionizer.correct_sphere_center(self.sphere_center.to_owned().insert_axis(Axis(0))).row(0).to_owned();
+
+        Ok(())
+    }
+
+    pub fn estimate_sphere_center(&mut self, from_2d: Option<Array1<f64>>, prior_3d: Option<Array1<f64>>, prior_strength: f64, calculate_rms_residual: bool) -> Result<(), DetectorError> {
+        let estimate = Self::fit_sphere_center(&self.fit_lines(), from_2d, prior_3d, prior_strength, calculate_rms_residual);
+        self.apply_sphere_center_estimate(estimate)
+    }
+
+    // Only touches the given lines so it can also run on a background thread
+    pub fn fit_sphere_center(lines: &[FitLines], from_2d: Option<Array1<f64>>, prior_3d: Option<Array1<f64>>, prior_strength: f64, calculate_rms_residual: bool) -> SphereCenterEstimate {
+        let projected_sphere_center = from_2d.unwrap_or_else(|| Self::estimate_sphere_center_2d(lines));
+        let sphere_center = Self::estimate_sphere_center_3d(lines, &projected_sphere_center, prior_3d, prior_strength);
+        let rms_residual = if calculate_rms_residual {
+            Self::calculate_rms_residual(lines, &projected_sphere_center, &sphere_center)
+        } else {
+            f64::NAN
+        };
+
+        SphereCenterEstimate {
+            projected_sphere_center,
+            sphere_center,
+            rms_residual
+        }
+    }
+
+    pub fn apply_sphere_center_estimate(&mut self, estimate: SphereCenterEstimate) -> Result<(), DetectorError> {
+        self.set_sphere_center(estimate.sphere_center)?;
+        self.projected_sphere_center = estimate.projected_sphere_center;
+        self.rms_residual = estimate.rms_residual;
+        self.has_fit = true;
+
+        Ok(())
+    }
+
+    pub fn fit_lines(&self) -> Vec<FitLines> {
+        self.storage.observations().into_iter().filter_map(Observation::fit_lines).collect()
+    }
+
+    pub fn estimate_sphere_center_2d(lines: &[FitLines]) -> Array1<f64> {
+        let mut sum_aux_2d: Array2<f64> = Array2::zeros((2, 3));
+        for line in lines {
+            sum_aux_2d += &line.aux_2d;
+        }
+
+        // Least-squares intersection of all projected gaze lines
+        let a = Matrix2::new(
+            sum_aux_2d[[0, 0]], sum_aux_2d[[0, 1]],
+            sum_aux_2d[[1, 0]], sum_aux_2d[[1, 1]]
+        );
+        let b = Vector2::new(sum_aux_2d[[0, 2]], sum_aux_2d[[1, 2]]);
+        let projected_sphere_center = a.pseudo_inverse(1e-12).unwrap() * b;
+
+        array!(projected_sphere_center[0], projected_sphere_center[1])
     }
 
-    pub fn estimate_sphere_center(&mut self, from_2d: Option<f64>, prior_3d: Option<f64>, prior_strength: f64, calculate_rms_residual: bool) {
-        self.projected_sphere_center = if from_2d.is_some() { from_2d.unwrap() } else { self.estimate_sphere_center_2d() };
-        //let spher
+    pub fn estimate_sphere_center_3d(lines: &[FitLines], projected_sphere_center: &Array1<f64>, prior_3d: Option<Array1<f64>>, prior_strength: f64) -> Array1<f64> {
+        let mut sum_aux_3d: Array2<f64> = Array2::zeros((3, 4));
+        for line in lines {
+            sum_aux_3d += &Self::disambiguated_aux_3d(line, projected_sphere_center);
+        }
+
+        // Blend the normalized line intersection problem with a pull towards the prior
+        if let Some(prior_3d) = prior_3d {
+            if !lines.is_empty() {
+                sum_aux_3d /= lines.len() as f64;
+            }
+            sum_aux_3d *= 1.0 - prior_strength;
+            for i in 0..3 {
+                sum_aux_3d[[i, i]] += prior_strength;
+                sum_aux_3d[[i, 3]] += prior_strength * prior_3d[i];
+            }
+        }
+
+        // Least-squares intersection of all Dierkes lines
+        let a = Matrix3::from_fn(|i, j| sum_aux_3d[[i, j]]);
+        let b = Vector3::new(sum_aux_3d[[0, 3]], sum_aux_3d[[1, 3]], sum_aux_3d[[2, 3]]);
+        let sphere_center = a.pseudo_inverse(1e-12).unwrap() * b;
+
+        array!(sphere_center[0], sphere_center[1], sphere_center[2])
     }
 
-    pub fn estimate_sphere_center_2d(&self) -> f64 {
-        let observations = self.storage.observations();
+    fn disambiguated_aux_3d(line: &FitLines, projected_sphere_center: &Array1<f64>) -> Array2<f64> {
+        // The first circle is only plausible if its projected gaze points away from the projected eye center
+        let index = if (&line.gaze_2d.origin - projected_sphere_center).dot(&line.gaze_2d.direction) < 0.0 { 1 } else { 0 };
 
-        let auxes = observations.iter().map(|x| x.clone().aux_2d.unwrap());
+        line.aux_3d.slice(s![index, .., ..]).to_owned()
+    }
 
-        let mut aux_2d_vec: Vec<Array2<f64>> = Vec::new();
+    fn calculate_rms_residual(lines: &[FitLines], projected_sphere_center: &Array1<f64>, sphere_center: &Array1<f64>) -> f64 {
+        if lines.is_empty() {
+            return f64::NAN;
+        }
 
-        // Iterate through each observation and push its aux_2d to the aux_2d_vec
-        for obs in auxes {
-            aux_2d_vec.push(obs.clone());
+        let mut sum_squared_residuals = 0.0;
+        for line in lines {
+            // (I - vv^T) c - (I - vv^T) o is the offset from the line to the sphere center
+            let aux_3d = Self::disambiguated_aux_3d(line, projected_sphere_center);
+            let residual = aux_3d.slice(s![.., ..3]).dot(sphere_center) - aux_3d.column(3);
+            sum_squared_residuals += residual.dot(&residual);
         }
 
-        // Concatenate all the arrays in aux_2d_vec along the first axis
-        let aux_2d = stack!(Axis(0), &aux_2d_vec);
+        (sum_squared_residuals / lines.len() as f64).sqrt()
+    }
 
-        // Reshape aux_2d to have shape (-1, 2, 3)
-        let aux_2d_reshaped = aux_2d.clone().into_shape((aux_2d.len() / 6, 2, 3)).unwrap();
+    pub fn predict_pupil_circle(&self, observation: &Observation) -> Circle3D {
+        let circle_3d_pair = match &observation.circle_3d_pair {
+            Some(pair) if !observation.invalid => pair,
+            _ => return Circle3D::null()
+        };
 
+        // Intersect the ray through the 2D pupil center with the eye sphere
+        let direction = array!(observation.ellipse.center[0], observation.ellipse.center[1], self.camera.focal_length());
+        let pupil_center = Self::nearest_intersection_point(&self.sphere_center, self.eye_parameters.pupil_distance, &direction);
+        let gaze_vector = &pupil_center - &self.sphere_center;
+        let gaze_vector = &gaze_vector / gaze_vector.dot(&gaze_vector).sqrt();
 
+        // Pick the unprojected circle that agrees best with the model and rescale it to the pupil's depth
+        let circle_3d = if circle_3d_pair[0].normal.dot(&gaze_vector) >= circle_3d_pair[1].normal.dot(&gaze_vector) {
+            &circle_3d_pair[0]
+        } else {
+            &circle_3d_pair[1]
+        };
+        let pupil_radius = circle_3d.radius * pupil_center.dot(&pupil_center).sqrt() / circle_3d.center.dot(&circle_3d.center).sqrt();
 
+        Circle3D {
+            center: pupil_center,
+            normal: gaze_vector,
+            radius: pupil_radius
+        }
+    }
+
+    // Where a refraction corrected pupil with the given gaze and radius shows up in the image, in pixels
+    pub fn predict_apparent_pupil_ellipse(&self, gaze_vector: &Array1<f64>, radius: f64) -> Result<Option<Ellipse>, DetectorError> {
+        let input = concatenate![
+            Axis(0),
+            self.sphere_center.view(),
+            gaze_vector.view(),
+            array![radius].view()
+        ].insert_axis(Axis(0));
+        let apparent = self.refractionizer.uncorrect_pupil_circle(input)?.row(0).to_owned();
+        let gaze_vector = apparent.slice(s![..3]).to_owned();
+        let gaze_vector = &gaze_vector / gaze_vector.dot(&gaze_vector).sqrt();
 
+        let circle = Circle3D {
+            center: &self.sphere_center + self.eye_parameters.pupil_distance * &gaze_vector,
+            normal: gaze_vector,
+            radius: apparent[3]
+        };
+        Ok(project_circle_into_image_plane(&circle, self.camera.focal_length())
+            .map(|ellipse| self.camera.internal_ellipse_to_image(&ellipse)))
+    }
 
+    fn nearest_intersection_point(sphere_center: &Array1<f64>, sphere_radius: f64, direction: &Array1<f64>) -> Array1<f64> {
+        let direction = direction / direction.dot(direction).sqrt();
+        let closest_approach = direction.dot(sphere_center);
+        let delta = closest_approach.powi(2) - sphere_center.dot(sphere_center) + sphere_radius.powi(2);
 
-        return 0.0
+        if delta >= 0.0 {
+            return (closest_approach - delta.sqrt()) * &direction
+        }
+
+        // The ray misses the sphere, fall back to the sphere point closest to it
+        let offset = closest_approach * &direction - sphere_center;
+        sphere_center + sphere_radius * &offset / offset.dot(&offset).sqrt()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observations::BasicStorage;
+    use crate::simulator::{EyeModel, EyeSimulator};
+
+    #[test]
+    fn apparent_pupil_ellipse_matches_simulated_image() {
+        // Anisotropic pixels and an off-center principal point make the internal frame differ from pixels
+        let camera = Arc::new(CameraModel::from_intrinsics(620.0, 580.0, 230.0, 170.0, array![400.0, 400.0]));
+        let sphere_center = array![3.0, -2.0, 35.0];
+        let simulator = EyeSimulator::new(camera.clone(), EyeModel::le_grand(sphere_center.clone()));
+        let mut model = TwoSphereModel::new(camera, EyeParameters::default(), Box::new(BasicStorage::new())).unwrap();
+        let apparent_sphere_center = model.refractionizer.uncorrect_sphere_center(sphere_center.insert_axis(Axis(0))).unwrap();
+        model.set_sphere_center(apparent_sphere_center.row(0).to_owned()).unwrap();
+
+        let gaze_vector = array![0.3, 0.1, -0.9486832980505138];
+        let simulated = simulator.pupil_datum(&simulator.simulate(&gaze_vector, 2.0).unwrap(), 1.0, 0.0).ellipse.to_ellipse();
+        let predicted = model.predict_apparent_pupil_ellipse(&gaze_vector, 2.0).unwrap().unwrap();
+
+        let center_offset = &predicted.center - &simulated.center;
+        assert!(center_offset.dot(&center_offset).sqrt() < 0.5, "center {} expected {}", predicted.center, simulated.center);
+        assert!((predicted.minor_radius - simulated.minor_radius).abs() < 0.5);
+        assert!((predicted.major_radius - simulated.major_radius).abs() < 0.5);
+    }
+}